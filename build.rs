@@ -0,0 +1,12 @@
+use std::env;
+
+fn main() {
+    let target = env::var("TARGET").unwrap();
+
+    println!("cargo:rustc-check-cfg=cfg(armv6m)");
+
+    // ARMv6-M cores have no DWT cycle counter
+    if target.starts_with("thumbv6m-") {
+        println!("cargo:rustc-cfg=armv6m");
+    }
+}
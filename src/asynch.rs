@@ -0,0 +1,125 @@
+//! Async primitives driven by the SysTick interrupt.
+//!
+//! The wakers of waiting tasks are woken on each SysTick interrupt, so the tasks
+//! re-check their condition once per tick. This works with any executor and
+//! needs no allocation.
+
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+
+use cortex_m::interrupt;
+
+use crate::{is_before, millis, ticks};
+
+/// Maximum number of tasks waiting at the same time.
+///
+/// When more tasks are waiting, the additional ones are woken immediately
+/// and effectively poll in a busy loop.
+pub const MAX_WAKERS: usize = 8;
+
+/// Wakers of the waiting tasks, `None` for unused slots.
+static mut WAKERS: [Option<Waker>; MAX_WAKERS] = [const { None }; MAX_WAKERS];
+
+/// Ticker for periodic async tasks.
+///
+/// The deadlines are advanced by the period each time, so the ticker doesn't drift.
+/// If the task falls behind by more than one period, the missed periods are
+/// coalesced into a single `next()` completion instead of firing back-to-back.
+#[derive(Debug, Clone)]
+pub struct AsyncTicker {
+    /// Time in milliseconds of the next deadline.
+    deadline: u64,
+    /// Period in milliseconds.
+    period: u64,
+}
+
+impl AsyncTicker {
+    /// Returns a ticker with a period in milliseconds, starting now.
+    pub fn every(period_ms: u32) -> Self {
+        let period = period_ms.max(1) as u64;
+
+        Self {
+            deadline: millis() + period,
+            period,
+        }
+    }
+
+    /// Waits until the next period is due.
+    pub async fn next(&mut self) {
+        poll_fn(|cx| {
+            // A tick between the check and the registration would not wake the task
+            let now = interrupt::free(|_| {
+                let now = millis();
+                if is_before(now, self.deadline) {
+                    register(cx.waker());
+                    return None;
+                }
+                Some(now)
+            });
+
+            let Some(now) = now else {
+                return Poll::Pending;
+            };
+
+            // Skip all periods that are already over
+            let missed = (now - self.deadline) / self.period;
+            self.deadline += (missed + 1) * self.period;
+
+            Poll::Ready(())
+        })
+        .await
+    }
+}
+
+/// Waits until the next tick.
+///
+/// Intended to spread work across ticks on cooperative executors. Any number of
+/// tasks can wait for the same tick, they are all woken by it.
+pub async fn yield_tick() {
+    let start = ticks();
+
+    poll_fn(|cx| {
+        // A tick between the check and the registration would not wake the task
+        interrupt::free(|_| {
+            if ticks() != start {
+                return Poll::Ready(());
+            }
+
+            register(cx.waker());
+            Poll::Pending
+        })
+    })
+    .await
+}
+
+/// Registers a waker to be woken on the next tick.
+fn register(waker: &Waker) {
+    let registered = interrupt::free(|_| unsafe {
+        if WAKERS.iter().flatten().any(|w| w.will_wake(waker)) {
+            return true;
+        }
+
+        match WAKERS.iter_mut().find(|w| w.is_none()) {
+            Some(slot) => {
+                *slot = Some(waker.clone());
+                true
+            }
+            None => false,
+        }
+    });
+
+    if !registered {
+        waker.wake_by_ref();
+    }
+}
+
+/// Wakes all waiting tasks. Called on each SysTick interrupt.
+pub(crate) fn on_tick() {
+    // Each slot is taken in its own critical section and woken outside of it
+    #[allow(clippy::needless_range_loop)]
+    for index in 0..MAX_WAKERS {
+        if let Some(waker) = interrupt::free(|_| unsafe { WAKERS[index].take() }) {
+            waker.wake();
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Builder for the timer initialization.
+
+use cortex_m::peripheral::scb::SystemHandler;
+use cortex_m::peripheral::SYST;
+
+#[cfg(not(feature = "no_callback"))]
+use crate::set_callback;
+use crate::{
+    check_frequencies, has_reference_clock, start, syst_clock_source, ClockSource, SysTickError,
+    CLOCK_FREQ, CLOCK_SOURCE, CORE_CLOCK_FREQ, DITHER, FROZEN_ELAPSED, INIT_TICKS, ONESHOT_FN,
+    ONESHOT_START, POLLING, RESET_GENERATION, SYSTICK, SYSTICK_COUNTER, TICK_FREQ, WAKEUP_ONLY,
+    WRAP_PENDING,
+};
+
+/// Builder collecting all init options of the timer.
+///
+/// Created with the peripheral, configured with the chainable methods and committed
+/// with `build()`, e.g.
+/// `SysTickBuilder::new(syst).clock_hz(168_000_000).tick_hz(1000).start(true).build()`.
+pub struct SysTickBuilder {
+    /// Peripheral, consumed on build.
+    syst: SYST,
+    /// Frequency of the clock source in Hz.
+    clock_hz: u32,
+    /// Tick frequency in Hz.
+    tick_hz: u32,
+    /// Clock source of the timer.
+    source: ClockSource,
+    /// Priority of the SysTick exception, unchanged if `None`.
+    priority: Option<u8>,
+    /// Start the counter after init.
+    start: bool,
+    /// Initial tick count.
+    offset: u64,
+    /// Callback function to set.
+    #[cfg(not(feature = "no_callback"))]
+    callback: Option<fn(u64)>,
+}
+
+impl SysTickBuilder {
+    /// Returns a new builder for the peripheral.
+    ///
+    /// The defaults are the core clock source, a tick frequency of 1kHz, an unchanged
+    /// priority and a stopped counter. The clock frequency must always be set.
+    pub fn new(syst: SYST) -> Self {
+        Self {
+            syst,
+            clock_hz: 0,
+            tick_hz: 1000,
+            source: ClockSource::Core,
+            priority: None,
+            start: false,
+            offset: 0,
+            #[cfg(not(feature = "no_callback"))]
+            callback: None,
+        }
+    }
+
+    /// Sets the frequency of the clock source in Hz.
+    pub fn clock_hz(mut self, clock_hz: u32) -> Self {
+        self.clock_hz = clock_hz;
+        self
+    }
+
+    /// Sets the tick frequency in Hz.
+    pub fn tick_hz(mut self, tick_hz: u32) -> Self {
+        self.tick_hz = tick_hz;
+        self
+    }
+
+    /// Sets the clock source, see `try_init_with_source()`.
+    pub fn source(mut self, source: ClockSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Sets the priority of the SysTick exception.
+    ///
+    /// Only the implemented upper bits of the value are used by the hardware.
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Sets if the counter is started by `build()`.
+    pub fn start(mut self, start: bool) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Sets the initial tick count, see `init_with_offset()`.
+    pub fn offset(mut self, initial_ticks: u64) -> Self {
+        self.offset = initial_ticks;
+        self
+    }
+
+    /// Sets the callback function, see `set_callback()`.
+    #[cfg(not(feature = "no_callback"))]
+    pub fn callback(mut self, callback: fn(u64)) -> Self {
+        self.callback = Some(callback);
+        self
+    }
+
+    /// Validates the configuration and initializes the timer.
+    ///
+    /// Returns an error without touching the timer if it is already initialized,
+    /// the external clock source is not implemented or the frequencies are out of range.
+    /// The peripheral is dropped in that case.
+    pub fn build(self) -> Result<(), SysTickError> {
+        if unsafe { SYSTICK.is_some() } {
+            return Err(SysTickError::AlreadyInitialized);
+        }
+
+        if self.source == ClockSource::External && !has_reference_clock() {
+            return Err(SysTickError::NoReferenceClock);
+        }
+
+        check_frequencies(self.clock_hz, self.tick_hz)?;
+
+        self.apply();
+        Ok(())
+    }
+
+    /// Initializes the timer without validating the configuration.
+    pub(crate) fn apply(self) {
+        let mut syst = self.syst;
+
+        // Make sure interrupt does not run while doing the init
+        syst.disable_interrupt();
+
+        syst.set_clock_source(syst_clock_source(self.source));
+
+        unsafe {
+            SYSTICK_COUNTER = self.offset;
+            INIT_TICKS = self.offset;
+            RESET_GENERATION = RESET_GENERATION.wrapping_add(1);
+            WRAP_PENDING = false;
+            POLLING = false;
+            DITHER = false;
+            WAKEUP_ONLY = false;
+            FROZEN_ELAPSED = 0;
+            ONESHOT_FN = None;
+            ONESHOT_START = 0;
+            CLOCK_SOURCE = self.source;
+
+            // These values need to be stored for further calculations
+            CLOCK_FREQ = self.clock_hz;
+            TICK_FREQ = self.tick_hz;
+            CORE_CLOCK_FREQ = match self.source {
+                ClockSource::Core => self.clock_hz,
+                ClockSource::External => 0,
+            };
+        }
+
+        // Setup the timer registers with the required values
+        let reload = (self.clock_hz / self.tick_hz) - 1;
+        syst.set_reload(reload);
+        syst.clear_current();
+
+        // Discard a wrap from before init, which must not be counted as the first tick.
+        // Writing the current value already clears COUNTFLAG, but an interrupt may still
+        // be pending from the previous configuration.
+        let _ = syst.has_wrapped();
+        cortex_m::peripheral::SCB::clear_pendst();
+
+        if let Some(priority) = self.priority {
+            // Only the SysTick priority field of the SCB is modified
+            unsafe {
+                cortex_m::Peripherals::steal()
+                    .SCB
+                    .set_priority(SystemHandler::SysTick, priority);
+            }
+        }
+
+        // Finally start the interrupt and let everything run
+        syst.enable_interrupt();
+
+        unsafe { SYSTICK = Some(syst) }
+
+        #[cfg(not(feature = "no_callback"))]
+        if let Some(callback) = self.callback {
+            set_callback(callback);
+        }
+
+        if self.start {
+            start();
+        }
+    }
+}
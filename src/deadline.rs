@@ -0,0 +1,168 @@
+//! Set of deadlines for many concurrent timeouts.
+
+use crate::{is_before, millis};
+
+/// Fixed-size set of absolute deadlines in milliseconds.
+///
+/// Each deadline is identified by an id of type `Id`. Instead of spinning on each
+/// timeout individually, `expired()` is called once per main loop iteration to get
+/// the ids of all deadlines that have passed. No allocation is needed.
+#[derive(Debug, Clone)]
+pub struct DeadlineSet<Id, const N: usize> {
+    /// Ids and deadlines, `None` for unused slots.
+    entries: [Option<(Id, u64)>; N],
+}
+
+impl<Id: Copy + PartialEq, const N: usize> DeadlineSet<Id, N> {
+    /// Returns a new empty set.
+    pub const fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// Inserts a deadline in milliseconds as returned by `millis()`.
+    ///
+    /// An existing deadline with the same id is replaced.
+    /// Returns the id as error when the set is full.
+    pub fn insert(&mut self, id: Id, deadline_ms: u64) -> Result<(), Id> {
+        let slot = match self
+            .entries
+            .iter()
+            .position(|e| matches!(e, Some((i, _)) if *i == id))
+        {
+            Some(index) => Some(index),
+            None => self.entries.iter().position(|e| e.is_none()),
+        };
+
+        match slot {
+            Some(index) => {
+                self.entries[index] = Some((id, deadline_ms));
+                Ok(())
+            }
+            None => Err(id),
+        }
+    }
+
+    /// Removes a deadline. Returns `false` if the id was not in the set.
+    pub fn remove(&mut self, id: Id) -> bool {
+        for entry in self.entries.iter_mut() {
+            if matches!(entry, Some((i, _)) if *i == id) {
+                *entry = None;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Returns if a deadline with the id is in the set.
+    pub fn contains(&self, id: Id) -> bool {
+        self.entries
+            .iter()
+            .any(|e| matches!(e, Some((i, _)) if *i == id))
+    }
+
+    /// Returns the number of deadlines in the set.
+    pub fn len(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_some()).count()
+    }
+
+    /// Returns if the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all expired deadlines and returns their ids, earliest deadline first.
+    ///
+    /// The current time is read once when calling this function. Comparison is
+    /// done with `is_before()`, so it is safe across a wrap of the timestamps.
+    /// Deadlines are only removed when the iterator yields them.
+    pub fn expired(&mut self) -> impl Iterator<Item = Id> + '_ {
+        self.expired_at(millis())
+    }
+
+    /// Same as `expired()`, but with the current time in milliseconds passed by the caller.
+    pub fn expired_at(&mut self, now: u64) -> impl Iterator<Item = Id> + '_ {
+        core::iter::from_fn(move || {
+            let mut earliest: Option<(usize, u64)> = None;
+
+            for (index, entry) in self.entries.iter().enumerate() {
+                if let Some((_, deadline)) = *entry {
+                    if is_before(now, deadline) {
+                        continue;
+                    }
+                    if earliest.is_none_or(|(_, d)| is_before(deadline, d)) {
+                        earliest = Some((index, deadline));
+                    }
+                }
+            }
+
+            let (index, _) = earliest?;
+            self.entries[index].take().map(|(id, _)| id)
+        })
+    }
+}
+
+impl<Id: Copy + PartialEq, const N: usize> Default for DeadlineSet<Id, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert() {
+        let mut set = DeadlineSet::<u8, 2>::new();
+        assert!(set.is_empty());
+
+        assert_eq!(set.insert(1, 100), Ok(()));
+        assert_eq!(set.insert(2, 200), Ok(()));
+        assert_eq!(set.insert(3, 300), Err(3));
+        assert_eq!(set.len(), 2);
+
+        // Same id replaces the deadline
+        assert_eq!(set.insert(1, 50), Ok(()));
+        assert_eq!(set.len(), 2);
+        assert!(set.expired_at(50).eq([1]));
+
+        assert!(set.remove(2));
+        assert!(!set.remove(2));
+        assert!(!set.contains(2));
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn expiry_order() {
+        let mut set = DeadlineSet::<u8, 4>::new();
+        set.insert(1, 300).unwrap();
+        set.insert(2, 100).unwrap();
+        set.insert(3, 500).unwrap();
+        set.insert(4, 200).unwrap();
+
+        assert!(set.expired_at(99).eq([]));
+        assert!(set.expired_at(300).eq([2, 4, 1]));
+        assert_eq!(set.len(), 1);
+        assert!(set.contains(3));
+
+        // Deadlines not yielded by the iterator stay in the set
+        set.insert(5, 400).unwrap();
+        assert_eq!(set.expired_at(1000).next(), Some(5));
+        assert!(set.contains(3));
+    }
+
+    #[test]
+    fn expiry_across_wrap() {
+        let mut set = DeadlineSet::<u8, 4>::new();
+        set.insert(1, 10).unwrap();
+        set.insert(2, u64::MAX - 10).unwrap();
+        set.insert(3, u64::MAX).unwrap();
+
+        assert!(set.expired_at(u64::MAX - 20).eq([]));
+        assert!(set.expired_at(u64::MAX - 5).eq([2]));
+        assert!(set.expired_at(5).eq([3]));
+        assert!(set.expired_at(10).eq([1]));
+        assert!(set.is_empty());
+    }
+}
@@ -2,16 +2,621 @@
 
 #![allow(dead_code)]
 
-use crate::{micros, millis};
+use core::convert::Infallible;
+
+use crate::{
+    clock_cycles, clock_freq, core_clock_freq, cycles_until_next_tick, is_before, micros,
+    micros_resolution, millis, muldiv_u64, reconcile_masked_ticks, SysTickError,
+};
+
+/// Number of core clock cycles per iteration of the `delay_cycles_exact()` loop.
+static mut LOOP_CYCLES: u32 = 3;
+
+/// Timer cycles spent in `delay_us()` before the short delays start counting.
+static mut SHORT_DELAY_OVERHEAD: u32 = 0;
+
+/// Source of the time used by the delay functions.
+///
+/// The delays read the SysTick timer via `SysTickSource`. With the `test_hooks`
+/// feature, another source can be installed with `set_time_source()`, e.g. a mock
+/// advancing on each call, so code calling the delays can be tested without
+/// waiting in real time. `delay_ms_wfi()` reads the time source as well, but still
+/// sleeps until the next interrupt in between. The cycle based delays and `delay_us()`
+/// below `SHORT_DELAY_US` always use the hardware.
+pub trait TimeSource: Sync {
+    /// Returns elapsed milliseconds.
+    fn millis(&self) -> u64;
+
+    /// Returns elapsed microseconds.
+    fn micros(&self) -> u64;
+}
+
+/// Time source reading the SysTick timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SysTickSource;
+
+impl TimeSource for SysTickSource {
+    fn millis(&self) -> u64 {
+        millis()
+    }
+
+    fn micros(&self) -> u64 {
+        micros()
+    }
+}
+
+/// Time source of the delay functions.
+#[cfg(feature = "test_hooks")]
+static mut TIME_SOURCE: &'static dyn TimeSource = &SysTickSource;
+
+/// Sets the time source of the delay functions.
+///
+/// Intended for tests only, see `TimeSource` for the delays that are affected.
+#[cfg(feature = "test_hooks")]
+pub fn set_time_source(source: &'static dyn TimeSource) {
+    // The reference is two words, so it must not be read half written by an interrupt
+    #[cfg(not(test))]
+    cortex_m::interrupt::free(|_| unsafe { TIME_SOURCE = source });
+
+    // Critical sections are not available in host tests
+    #[cfg(test)]
+    unsafe {
+        TIME_SOURCE = source;
+    }
+}
+
+/// Restores the SysTick timer as time source of the delay functions.
+#[cfg(feature = "test_hooks")]
+pub fn reset_time_source() {
+    set_time_source(&SysTickSource);
+}
+
+/// Returns elapsed milliseconds from the time source.
+#[inline(always)]
+fn now_ms() -> u64 {
+    #[cfg(feature = "test_hooks")]
+    return unsafe { TIME_SOURCE.millis() };
+
+    #[cfg(not(feature = "test_hooks"))]
+    millis()
+}
+
+/// Returns elapsed microseconds from the time source.
+#[inline(always)]
+fn now_us() -> u64 {
+    #[cfg(feature = "test_hooks")]
+    return unsafe { TIME_SOURCE.micros() };
+
+    #[cfg(not(feature = "test_hooks"))]
+    micros()
+}
 
 /// Sleep for a number of milliseconds.
 pub fn delay_ms(value: u32) {
-    let start = millis();
-    while millis() < start + value as u64 {}
+    let start = now_ms();
+    while now_ms() < start + value as u64 {}
+}
+
+/// Sleep for a number of milliseconds, waiting for interrupts in between.
+///
+/// The core sleeps until the next interrupt, which is at least the next tick.
+/// The time is accounted as idle for `load::cpu_load_percent()`.
+pub fn delay_ms_wfi(value: u32) {
+    let start = now_ms();
+    while now_ms() < start + value as u64 {
+        crate::load::idle();
+    }
+}
+
+/// Sleep for a number of milliseconds, calling `poll` repeatedly while waiting.
+///
+/// Always waits for the full duration, `poll` can be used to advance a state machine
+/// or a protocol stack in the meantime. The delay is extended by the runtime of the
+/// last `poll` call.
+pub fn delay_ms_polling(value: u32, mut poll: impl FnMut()) {
+    let start = now_ms();
+    while now_ms() < start + value as u64 {
+        poll();
+    }
+}
+
+/// Sleep for a number of milliseconds in steps, reporting the progress after each step.
+///
+/// `on_step` is called with the elapsed fraction of the total time from 0.0 to 1.0.
+/// The last step may be shorter than `step_ms`, so `on_step(1.0)` is always called
+/// once at the end, even if `step_ms` is larger than `total_ms` or 0.
+pub fn delay_with_progress(total_ms: u32, step_ms: u32, mut on_step: impl FnMut(f32)) {
+    let step_ms = if step_ms == 0 { total_ms } else { step_ms };
+    let start = now_ms();
+    let mut elapsed = 0;
+
+    loop {
+        // Steps are relative to the start, so the runtime of `on_step` doesn't add up
+        elapsed += step_ms.min(total_ms - elapsed);
+        while now_ms() < start + elapsed as u64 {}
+
+        if elapsed == total_ms {
+            on_step(1.0);
+            break;
+        }
+
+        on_step(elapsed as f32 / total_ms as f32);
+    }
 }
 
 /// Sleep for a number of microseconds.
+///
+/// Delays below `SHORT_DELAY_US` are converted to timer cycles once and counted by
+/// polling the current value register, because reading `micros()` on each iteration
+/// can take longer than a microsecond on slow cores. Longer delays poll `micros()`,
+/// which allows the delay to be interrupted for more than a tick period.
+///
+/// The conversion and the register reads before the short delays start counting take
+/// some time, which makes very short delays noticeably longer, especially on
+/// Cortex-M0 without a hardware divider. `calibrate_short_delay_overhead()` measures
+/// that time, so it's subtracted from the delay afterwards.
 pub fn delay_us(value: u32) {
-    let start = micros();
-    while micros() < start + value as u64 {}
+    if value < SHORT_DELAY_US {
+        let overhead = unsafe { SHORT_DELAY_OVERHEAD };
+        delay_timer_cycles(short_delay_cycles(value, clock_freq(), overhead));
+        return;
+    }
+
+    let start = now_us();
+    while now_us() < start + value as u64 {}
+}
+
+/// Sleep for one bit period at a baud rate, e.g. for bit-banged serial communication.
+///
+/// The bit period is converted to timer cycles directly, without rounding to full
+/// microseconds, and counted like the short delays of `delay_us()`.
+pub fn delay_bit(baud: u32) {
+    delay_bits(baud, 1);
+}
+
+/// Sleep for a number of bit periods at a baud rate.
+///
+/// The total is converted at once, so the rounding error doesn't accumulate over the bits.
+pub fn delay_bits(baud: u32, n: u32) {
+    delay_timer_cycles(bit_cycles(baud, n, clock_freq()));
+}
+
+/// Returns the timer cycles of a number of bit periods at a baud rate.
+const fn bit_cycles(baud: u32, n: u32, clock: u32) -> u64 {
+    muldiv_u64(n as u64, clock as u64, baud as u64)
+}
+
+/// Delays below this number of microseconds are counted in timer cycles by `delay_us()`.
+///
+/// The short delays don't use the time source, see `TimeSource`.
+pub const SHORT_DELAY_US: u32 = 100;
+
+/// Sleep for a number of timer cycles by polling the current value register.
+///
+/// The register must be polled at least once per tick period, so interrupts taking
+/// longer than that make the delay longer than requested.
+fn delay_timer_cycles(cycles: u64) {
+    let Some(syst) = (unsafe { crate::SYSTICK.as_ref() }) else {
+        return;
+    };
+
+    let period = syst.rvr.read() as u64 + 1;
+    let mut last = syst.cvr.read();
+    let mut elapsed = 0;
+
+    while elapsed < cycles {
+        let current = syst.cvr.read();
+        elapsed += counter_step(last, current, period);
+        last = current;
+    }
+}
+
+/// Returns the timer cycles of a short delay in microseconds, minus the entry overhead.
+const fn short_delay_cycles(value: u32, clock: u32, overhead: u32) -> u64 {
+    (value as u64 * clock as u64 / 1000000).saturating_sub(overhead as u64)
+}
+
+/// Returns the timer cycles between two reads of the current value register.
+const fn counter_step(last: u32, current: u32, period: u64) -> u64 {
+    // The counter counts down and restarts from the reload value on a wrap
+    if current <= last {
+        (last - current) as u64
+    } else {
+        last as u64 + period - current as u64
+    }
+}
+
+/// Sleep for a number of milliseconds and return the actual elapsed microseconds.
+///
+/// Same as `delay_ms()`, the result includes the granularity of the tick and any
+/// interrupts during the delay.
+pub fn delay_ms_measured(value: u32) -> u64 {
+    let start = now_us();
+    delay_ms(value);
+    now_us() - start
+}
+
+/// Sleep for a number of microseconds and return the actual elapsed microseconds.
+///
+/// Same as `delay_us()`, the result includes any interrupts during the delay.
+pub fn delay_us_measured(value: u32) -> u64 {
+    let start = now_us();
+    delay_us(value);
+    now_us() - start
+}
+
+/// Sleep for a number of microseconds if the configuration can resolve it.
+///
+/// Returns an error without waiting if `value` is below the resolution of `micros()`,
+/// see `micros_resolution()`. `delay_us()` would return after an arbitrary time up to
+/// the resolution in that case.
+pub fn try_delay_us(value: u32) -> Result<(), SysTickError> {
+    if value < micros_resolution() {
+        return Err(SysTickError::BelowResolution);
+    }
+
+    delay_us(value);
+    Ok(())
+}
+
+/// Sleep for a number of cycles of a peripheral clock.
+///
+/// Converts the cycles of a peripheral clock running at `peripheral_hz` into timer clock
+/// cycles, rounding up so the delay is never shorter than requested. Returns without
+/// waiting if `peripheral_hz` is 0.
+pub fn delay_peripheral_cycles(peripheral_hz: u32, cycles: u32) {
+    let timer_cycles = peripheral_to_timer_cycles(peripheral_hz, cycles, clock_freq());
+
+    let start = clock_cycles();
+    while clock_cycles() - start < timer_cycles {}
+}
+
+/// Returns the timer cycles covering a number of peripheral clock cycles, rounded up.
+const fn peripheral_to_timer_cycles(peripheral_hz: u32, cycles: u32, clock: u32) -> u64 {
+    if peripheral_hz == 0 {
+        return 0;
+    }
+
+    (cycles as u64 * clock as u64).div_ceil(peripheral_hz as u64)
+}
+
+/// Sleep for a number of core clock cycles using a calibrated busy loop.
+///
+/// The loop runs in assembly and does not read the SysTick timer, so it can be used
+/// for sub-microsecond delays, e.g. when bit-banging WS2812 pulses. The number of
+/// cycles per loop iteration must match the core, see `set_loop_cycles()` and
+/// `calibrate_loop_cycles()`.
+///
+/// Jitter budget: the delay is rounded down to a multiple of the loop timing and
+/// never shorter than one iteration. The call itself adds a few cycles, more on cores
+/// without hardware divide (Cortex-M0/M0+). Any interrupt that fires during the loop
+/// extends the delay by its full execution time, so wrap timing-critical sequences
+/// into `cortex_m::interrupt::free()`.
+#[inline]
+pub fn delay_cycles_exact(cycles: u32) {
+    busy_loop((cycles / unsafe { LOOP_CYCLES }).max(1));
+}
+
+/// Runs the loop of `delay_cycles_exact()` for a number of iterations, which must not be 0.
+///
+/// Other targets than Cortex-M, e.g. host builds, use a plain loop without cycle accuracy.
+#[inline(always)]
+fn busy_loop(iterations: u32) {
+    #[cfg(target_arch = "arm")]
+    unsafe {
+        core::arch::asm!(
+            // The timing of the branch depends on the alignment of its target on some cores
+            ".p2align 3",
+            // Local label other than 0 and 1, which LLVM can misparse as binary literals
+            "2:",
+            "subs {0}, #1",
+            "bne 2b",
+            inout(reg) iterations => _,
+            options(nomem, nostack),
+        );
+    }
+
+    #[cfg(not(target_arch = "arm"))]
+    for _ in 0..iterations {
+        core::hint::spin_loop();
+    }
+}
+
+/// Sleep for a number of microseconds with all interrupts disabled.
+///
+/// Intended for protocols with hard timing windows, e.g. bit-banged 1-Wire, where no
+/// interrupt may extend the delay. Since the SysTick interrupt can't fire either,
+/// `micros()` is not used for waiting. The delay is converted to core clock cycles up
+/// front and counted with the DWT cycle counter if it is enabled, otherwise with the
+/// calibrated loop of `delay_cycles_exact()`. If the core clock frequency is unknown,
+/// see `core_clock_freq()`, the frequency of the clock source is used instead.
+/// Ticks missed while the interrupts were disabled are added to the tick count
+/// afterwards, the callback function is only called once for them.
+pub fn delay_us_uninterrupted(value: u32) {
+    let core_freq = match core_clock_freq() {
+        0 => clock_freq(),
+        freq => freq,
+    };
+    let cycles = muldiv_u64(value as u64, core_freq as u64, 1000000);
+    let tick_cycles = muldiv_u64(value as u64, clock_freq() as u64, 1000000);
+
+    cortex_m::interrupt::free(|_| {
+        let start = cycles_until_next_tick();
+
+        #[cfg(not(armv6m))]
+        if cortex_m::peripheral::DWT::cycle_counter_enabled() {
+            // Wait in chunks the 32-bit counter can't overrun unnoticed
+            let mut begin = cortex_m::peripheral::DWT::cycle_count();
+            let mut remaining = cycles;
+            while remaining > 0 {
+                let chunk = remaining.min(1 << 31) as u32;
+                while cortex_m::peripheral::DWT::cycle_count().wrapping_sub(begin) < chunk {}
+                begin = begin.wrapping_add(chunk);
+                remaining -= chunk as u64;
+            }
+            reconcile_masked_ticks(start, tick_cycles);
+            return;
+        }
+
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let chunk = remaining.min(u32::MAX as u64) as u32;
+            delay_cycles_exact(chunk);
+            remaining -= chunk as u64;
+        }
+        reconcile_masked_ticks(start, tick_cycles);
+    });
+}
+
+/// Sets the number of core clock cycles per iteration of the `delay_cycles_exact()` loop.
+///
+/// The default value of 3 matches most Cortex-M0+/M3/M4 cores.
+/// Cortex-M7 cores typically need a value of 1.
+pub fn set_loop_cycles(cycles: u32) {
+    unsafe { LOOP_CYCLES = cycles.max(1) }
+}
+
+/// Returns the number of core clock cycles per iteration of the `delay_cycles_exact()` loop.
+pub fn loop_cycles() -> u32 {
+    unsafe { LOOP_CYCLES }
+}
+
+/// Measures the loop timing of `delay_cycles_exact()` using the SysTick timer.
+///
+/// The counter must be started before calling this function. The measurement is
+/// repeated a few times and the fastest run is used, so interrupts firing during
+/// the calibration don't affect the result. The DWT cycle counter is used instead
+/// if it is enabled. Otherwise, the timer cycles are scaled to core clock cycles,
+/// which requires `core_clock_freq()` with the external clock source.
+pub fn calibrate_loop_cycles() {
+    const ITERATIONS: u32 = 1000;
+
+    #[cfg(not(armv6m))]
+    let dwt = cortex_m::peripheral::DWT::cycle_counter_enabled();
+    #[cfg(armv6m)]
+    let dwt = false;
+
+    // Only the differences are used, so truncating to 32 bits is fine
+    let now = || -> u32 {
+        #[cfg(not(armv6m))]
+        if dwt {
+            return cortex_m::peripheral::DWT::cycle_count();
+        }
+        clock_cycles() as u32
+    };
+
+    let mut best = u32::MAX;
+
+    for _ in 0..4 {
+        let start = now();
+        busy_loop(1);
+        let base = now();
+        busy_loop(ITERATIONS + 1);
+        let end = now();
+
+        // Subtract the overhead of a single iteration and the timestamp reads
+        let cycles = end
+            .wrapping_sub(base)
+            .saturating_sub(base.wrapping_sub(start));
+        best = best.min(cycles);
+    }
+
+    let (core_freq, timer_freq) = match (dwt, core_clock_freq()) {
+        (true, _) | (false, 0) => (1, 1),
+        (false, core_freq) => (core_freq, clock_freq()),
+    };
+
+    set_loop_cycles(cycles_per_iteration(
+        best, ITERATIONS, core_freq, timer_freq,
+    ));
+}
+
+/// Sets the timer cycles subtracted from the short delays of `delay_us()`.
+pub fn set_short_delay_overhead(cycles: u32) {
+    unsafe { SHORT_DELAY_OVERHEAD = cycles }
+}
+
+/// Returns the timer cycles subtracted from the short delays of `delay_us()`.
+///
+/// 0 unless set with `set_short_delay_overhead()` or `calibrate_short_delay_overhead()`.
+pub fn short_delay_overhead() -> u32 {
+    unsafe { SHORT_DELAY_OVERHEAD }
+}
+
+/// Measures the time `delay_us()` takes before the short delays start counting.
+///
+/// The counter must be started before calling this function. Same as
+/// `calibrate_loop_cycles()`, the fastest of a few runs is used.
+pub fn calibrate_short_delay_overhead() {
+    let mut best = u64::MAX;
+
+    for _ in 0..4 {
+        let start = clock_cycles();
+        let base = clock_cycles();
+        delay_us(core::hint::black_box(0));
+        let end = clock_cycles();
+
+        // Subtract the overhead of the timestamp reads
+        let cycles = (end - base).saturating_sub(base - start);
+        best = best.min(cycles);
+    }
+
+    set_short_delay_overhead(best.min(u32::MAX as u64) as u32);
+}
+
+/// Returns the core clock cycles per loop iteration from a measurement in timer cycles.
+const fn cycles_per_iteration(
+    cycles: u32,
+    iterations: u32,
+    core_freq: u32,
+    timer_freq: u32,
+) -> u32 {
+    let cycles = muldiv_u64(cycles as u64, core_freq as u64, timer_freq as u64);
+    ((cycles + iterations as u64 / 2) / iterations as u64) as u32
+}
+
+/// Starts a non-blocking delay of a number of milliseconds.
+///
+/// Returns a handle that must be polled until the delay is over.
+/// Multiple delays can be in flight at the same time.
+pub fn start_ms(value: u32) -> DelayHandle {
+    DelayHandle {
+        deadline: now_ms() + value as u64,
+    }
+}
+
+/// Handle of a non-blocking delay created by `start_ms()`.
+#[derive(Debug, Clone, Copy)]
+pub struct DelayHandle {
+    /// Time in milliseconds when the delay is over.
+    deadline: u64,
+}
+
+impl DelayHandle {
+    /// Returns `Ok(())` when the delay is over, `WouldBlock` otherwise.
+    pub fn poll(&self) -> nb::Result<(), Infallible> {
+        if is_before(now_ms(), self.deadline) {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_delay() {
+        assert_eq!(short_delay_cycles(0, 168000000, 0), 0);
+        assert_eq!(short_delay_cycles(1, 168000000, 0), 168);
+        assert_eq!(short_delay_cycles(SHORT_DELAY_US - 1, 168000000, 0), 16632);
+        assert_eq!(short_delay_cycles(SHORT_DELAY_US - 1, u32::MAX, 0), 425201);
+        assert_eq!(short_delay_cycles(10, 8000000, 0), 80);
+    }
+
+    #[test]
+    fn short_delay_compensation() {
+        assert_eq!(short_delay_cycles(2, 48000000, 40), 56);
+        assert_eq!(short_delay_cycles(10, 8000000, 30), 50);
+
+        // Delays shorter than the overhead only take the overhead
+        assert_eq!(short_delay_cycles(1, 8000000, 30), 0);
+        assert_eq!(short_delay_cycles(0, 168000000, 40), 0);
+    }
+
+    #[test]
+    fn counter_steps() {
+        let period = 1000;
+
+        assert_eq!(counter_step(500, 500, period), 0);
+        assert_eq!(counter_step(500, 400, period), 100);
+
+        // Wrapped from 0 to the reload value 999
+        assert_eq!(counter_step(100, 900, period), 200);
+        assert_eq!(counter_step(0, 999, period), 1);
+
+        // Polling a counting down sequence across a wrap sums up to the cycles passed
+        let reads = [300, 180, 40, 950, 820, 610];
+        let elapsed: u64 = reads
+            .windows(2)
+            .map(|w| counter_step(w[0], w[1], period))
+            .sum();
+        assert_eq!(elapsed, 300 + 1000 - 610);
+    }
+
+    #[test]
+    fn loop_calibration() {
+        assert_eq!(cycles_per_iteration(3000, 1000, 1, 1), 3);
+        assert_eq!(cycles_per_iteration(1049, 1000, 1, 1), 1);
+        assert_eq!(cycles_per_iteration(2499, 1000, 1, 1), 2);
+        assert_eq!(cycles_per_iteration(2500, 1000, 1, 1), 3);
+
+        // Timer on the external clock source at core clock / 8
+        assert_eq!(cycles_per_iteration(375, 1000, 168000000, 21000000), 3);
+        assert_eq!(cycles_per_iteration(125, 1000, 168000000, 21000000), 1);
+    }
+
+    #[test]
+    fn peripheral_cycles() {
+        // APB clock at half the core clock
+        assert_eq!(peripheral_to_timer_cycles(84000000, 10, 168000000), 20);
+
+        // Rounded up to never wait too short
+        assert_eq!(peripheral_to_timer_cycles(32768, 1, 168000000), 5127);
+        assert_eq!(peripheral_to_timer_cycles(48000000, 1, 168000000), 4);
+        assert_eq!(peripheral_to_timer_cycles(48000000, 2, 168000000), 7);
+
+        assert_eq!(peripheral_to_timer_cycles(48000000, 0, 168000000), 0);
+        assert_eq!(peripheral_to_timer_cycles(0, 100, 168000000), 0);
+    }
+
+    #[test]
+    fn bit_periods() {
+        assert_eq!(bit_cycles(115200, 1, 168000000), 1458);
+        assert_eq!(bit_cycles(9600, 1, 8000000), 833);
+
+        // The total doesn't accumulate the rounding error of a single bit
+        assert_eq!(bit_cycles(115200, 10, 168000000), 14583);
+        assert_eq!(bit_cycles(9600, 1000, 8000000), 833333);
+
+        assert_eq!(bit_cycles(115200, 0, 168000000), 0);
+        assert_eq!(
+            bit_cycles(1, u32::MAX, u32::MAX),
+            u32::MAX as u64 * u32::MAX as u64
+        );
+    }
+
+    #[cfg(feature = "test_hooks")]
+    #[test]
+    fn mock_time_source() {
+        use core::sync::atomic::{AtomicU64, Ordering};
+
+        /// Advances by 1ms on each call.
+        struct MockSource(AtomicU64);
+
+        impl TimeSource for MockSource {
+            fn millis(&self) -> u64 {
+                self.0.fetch_add(1, Ordering::Relaxed)
+            }
+
+            fn micros(&self) -> u64 {
+                self.0.load(Ordering::Relaxed) * 1000
+            }
+        }
+
+        static SOURCE: MockSource = MockSource(AtomicU64::new(0));
+
+        set_time_source(&SOURCE);
+
+        let mut polls = 0;
+        delay_ms_polling(10, || polls += 1);
+        assert_eq!(polls, 9);
+
+        delay_ms(5);
+        assert_eq!(SOURCE.0.load(Ordering::Relaxed), 17);
+
+        reset_time_source();
+    }
 }
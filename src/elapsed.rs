@@ -0,0 +1,99 @@
+//! Measurement of elapsed time between two points.
+
+use crate::{clock_cycles, clock_freq, muldiv_u64};
+
+/// Point in time returned by `mark()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    /// Clock cycles at the time of the mark.
+    cycles: u64,
+}
+
+/// Time elapsed since a `Mark`, returned by `since()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Elapsed {
+    /// Elapsed clock cycles.
+    cycles: u64,
+}
+
+impl Elapsed {
+    /// Returns the elapsed clock cycles.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Returns the elapsed microseconds, truncated.
+    pub fn micros(&self) -> u64 {
+        self.micros_at(clock_freq())
+    }
+
+    /// Returns the elapsed milliseconds, truncated.
+    pub fn millis(&self) -> u64 {
+        self.millis_at(clock_freq())
+    }
+
+    /// Returns the elapsed microseconds at a clock frequency.
+    fn micros_at(&self, clock: u32) -> u64 {
+        muldiv_u64(self.cycles, 1000000, clock as u64)
+    }
+
+    /// Returns the elapsed milliseconds at a clock frequency.
+    fn millis_at(&self, clock: u32) -> u64 {
+        muldiv_u64(self.cycles, 1000, clock as u64)
+    }
+}
+
+/// Returns the current point in time for measuring with `since()`.
+pub fn mark() -> Mark {
+    Mark {
+        cycles: clock_cycles(),
+    }
+}
+
+/// Returns the time elapsed since a mark.
+///
+/// The subtraction wraps around, so a mark taken before a wrap of the cycle
+/// counter still gives the right result.
+pub fn since(mark: Mark) -> Elapsed {
+    since_at(mark, clock_cycles())
+}
+
+/// Returns the time elapsed from a mark to the clock cycles `now`.
+fn since_at(mark: Mark, now: u64) -> Elapsed {
+    Elapsed {
+        cycles: now.wrapping_sub(mark.cycles),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elapsed() {
+        let mark = Mark { cycles: 1000 };
+
+        let elapsed = since_at(mark, 168001000);
+        assert_eq!(elapsed.cycles(), 168000000);
+        assert_eq!(elapsed.micros_at(168000000), 1000000);
+        assert_eq!(elapsed.millis_at(168000000), 1000);
+
+        // Truncated to full units
+        let elapsed = since_at(mark, 1000 + 167);
+        assert_eq!(elapsed.micros_at(168000000), 0);
+        assert_eq!(since_at(mark, 1000 + 168 * 1999).millis_at(168000000), 1);
+
+        assert!(since_at(mark, 2000) < since_at(mark, 3000));
+    }
+
+    #[test]
+    fn elapsed_across_wrap() {
+        let mark = Mark {
+            cycles: u64::MAX - 99,
+        };
+
+        assert_eq!(since_at(mark, u64::MAX).cycles(), 99);
+        assert_eq!(since_at(mark, 0).cycles(), 100);
+        assert_eq!(since_at(mark, 400).cycles(), 500);
+    }
+}
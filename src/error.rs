@@ -0,0 +1,21 @@
+//! Error type of the fallible functions.
+
+/// Errors returned by the fallible functions of this crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SysTickError {
+    /// The timer was not initialized or was released with `free()`.
+    NotInitialized,
+    /// The timer was already initialized and must be released with `free()` first.
+    AlreadyInitialized,
+    /// The tick frequency is too low, the reload value doesn't fit into 24 bits.
+    ReloadOutOfRange,
+    /// The clock frequency is too low for the tick frequency, it must be at least twice as high.
+    ClockTooLow,
+    /// The operation didn't complete in time.
+    TimedOut,
+    /// The requested delay is below the resolution of the clock.
+    BelowResolution,
+    /// The external reference clock was selected but is not implemented.
+    NoReferenceClock,
+}
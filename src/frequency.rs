@@ -0,0 +1,131 @@
+//! Frequency measurement of external events.
+
+use crate::micros;
+
+/// Estimates the frequency of external events from their timestamps.
+///
+/// Call `on_event()` for each event, e.g. from a tachometer pulse interrupt.
+/// The interval between events is measured with `micros()` and smoothed.
+#[derive(Debug, Clone, Copy)]
+pub struct FrequencyCounter {
+    /// Timestamp of the last event in microseconds.
+    last: Option<u64>,
+    /// Smoothed interval between events in microseconds, `None` until two events were recorded.
+    interval: Option<f32>,
+    /// Weight of the previous interval when smoothing.
+    smoothing: f32,
+}
+
+impl FrequencyCounter {
+    /// Returns a new counter.
+    ///
+    /// `smoothing` is the weight of the previous interval from 0.0 to 1.0 when adding
+    /// a new one. A value of 0.0 disables smoothing, higher values give more stable
+    /// but slower responding results. Values out of range are clamped.
+    pub const fn new(smoothing: f32) -> Self {
+        let smoothing = if smoothing.is_nan() || smoothing < 0.0 {
+            0.0
+        } else if smoothing > 1.0 {
+            1.0
+        } else {
+            smoothing
+        };
+
+        Self {
+            last: None,
+            interval: None,
+            smoothing,
+        }
+    }
+
+    /// Records an event.
+    pub fn on_event(&mut self) {
+        self.on_event_at(micros());
+    }
+
+    /// Records an event at a timestamp in microseconds, e.g. captured by a timer.
+    ///
+    /// A timestamp before the previous one, e.g. after `reset()` of the tick count,
+    /// is taken as an interval of 0.
+    pub fn on_event_at(&mut self, now_us: u64) {
+        if let Some(last) = self.last {
+            let interval = now_us.saturating_sub(last) as f32;
+            self.interval = Some(match self.interval {
+                Some(previous) => previous * self.smoothing + interval * (1.0 - self.smoothing),
+                // First interval, nothing to smooth yet
+                None => interval,
+            });
+        }
+
+        self.last = Some(now_us);
+    }
+
+    /// Returns the estimated frequency in Hz.
+    ///
+    /// Returns 0.0 until at least two events were recorded or if the smoothed
+    /// interval is 0.
+    pub fn frequency_hz(&self) -> f32 {
+        match self.interval {
+            Some(interval) if interval > 0.0 => 1000000.0 / interval,
+            _ => 0.0,
+        }
+    }
+
+    /// Discards all recorded events.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.interval = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequency() {
+        let mut counter = FrequencyCounter::new(0.0);
+        assert_eq!(counter.frequency_hz(), 0.0);
+
+        counter.on_event_at(1000);
+        assert_eq!(counter.frequency_hz(), 0.0);
+
+        counter.on_event_at(2000);
+        assert_eq!(counter.frequency_hz(), 1000.0);
+
+        counter.on_event_at(2500);
+        assert_eq!(counter.frequency_hz(), 2000.0);
+
+        counter.reset();
+        counter.on_event_at(3000);
+        assert_eq!(counter.frequency_hz(), 0.0);
+    }
+
+    #[test]
+    fn smoothing() {
+        let mut counter = FrequencyCounter::new(0.5);
+        counter.on_event_at(0);
+        counter.on_event_at(1000);
+        counter.on_event_at(3000);
+        assert_eq!(counter.frequency_hz(), 1000000.0 / 1500.0);
+
+        // Two events in the same microsecond don't restart the smoothing
+        counter.on_event_at(3000);
+        assert_eq!(counter.frequency_hz(), 1000000.0 / 750.0);
+
+        assert_eq!(FrequencyCounter::new(2.0).smoothing, 1.0);
+        assert_eq!(FrequencyCounter::new(-1.0).smoothing, 0.0);
+        assert_eq!(FrequencyCounter::new(f32::NAN).smoothing, 0.0);
+    }
+
+    #[test]
+    fn time_going_backwards() {
+        let mut counter = FrequencyCounter::new(0.0);
+        counter.on_event_at(5000);
+        counter.on_event_at(100);
+        assert_eq!(counter.frequency_hz(), 0.0);
+
+        counter.on_event_at(600);
+        assert_eq!(counter.frequency_hz(), 2000.0);
+    }
+}
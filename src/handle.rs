@@ -0,0 +1,89 @@
+//! Handle with a selectable counter width.
+//!
+//! The free functions always return `u64` values. `SysTick<u32>` returns the same values
+//! truncated to 32 bits, which are cheaper to handle on cores without 64-bit arithmetic.
+//! Generic drivers can be written against `SysTick<T>` and used with either width.
+
+use core::marker::PhantomData;
+
+use crate::{clock_cycles, micros, millis, nanos, ticks};
+
+/// Counter type of a `SysTick` handle.
+pub trait TickCount: Copy + Ord {
+    /// Returns the value truncated to the counter width.
+    fn from_u64(value: u64) -> Self;
+
+    /// Returns `self - other`, wrapping around at the counter width.
+    fn wrapping_sub(self, other: Self) -> Self;
+}
+
+impl TickCount for u32 {
+    fn from_u64(value: u64) -> Self {
+        value as u32
+    }
+
+    fn wrapping_sub(self, other: Self) -> Self {
+        u32::wrapping_sub(self, other)
+    }
+}
+
+impl TickCount for u64 {
+    fn from_u64(value: u64) -> Self {
+        value
+    }
+
+    fn wrapping_sub(self, other: Self) -> Self {
+        u64::wrapping_sub(self, other)
+    }
+}
+
+/// Handle to the global timer with values of type `T`.
+///
+/// The handle has no state, it only selects the width of the returned values.
+/// The timer must be initialized with one of the init functions before use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SysTick<T: TickCount = u64> {
+    _width: PhantomData<T>,
+}
+
+impl<T: TickCount> SysTick<T> {
+    /// Returns a new handle.
+    pub const fn new() -> Self {
+        Self {
+            _width: PhantomData,
+        }
+    }
+
+    /// Returns the tick count, see `ticks()`.
+    pub fn ticks(&self) -> T {
+        T::from_u64(ticks())
+    }
+
+    /// Returns the number of clock cycles, see `clock_cycles()`.
+    pub fn clock_cycles(&self) -> T {
+        T::from_u64(clock_cycles())
+    }
+
+    /// Returns elapsed milliseconds, see `millis()`.
+    pub fn millis(&self) -> T {
+        T::from_u64(millis())
+    }
+
+    /// Returns elapsed microseconds, see `micros()`.
+    pub fn micros(&self) -> T {
+        T::from_u64(micros())
+    }
+
+    /// Returns elapsed nanoseconds, see `nanos()`.
+    pub fn nanos(&self) -> T {
+        T::from_u64(nanos())
+    }
+
+    /// Returns the difference between a value of this handle and an earlier one.
+    ///
+    /// The result is correct across a wrap of the counter width, as long as
+    /// less than a full wrap passed between both values.
+    pub fn elapsed(&self, now: T, earlier: T) -> T {
+        now.wrapping_sub(earlier)
+    }
+}
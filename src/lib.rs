@@ -1,29 +1,194 @@
 #![doc = include_str!("../README.md")]
 #![no_std]
 #![allow(dead_code)]
+#![allow(static_mut_refs)]
 
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod builder;
+pub mod deadline;
 pub mod delay;
+pub mod elapsed;
+pub mod error;
+pub mod frequency;
+pub mod handle;
+pub mod load;
+pub mod timeout;
+pub mod timers;
+pub mod units;
+
+pub use builder::SysTickBuilder;
+pub use elapsed::{mark, since, Elapsed, Mark};
+pub use error::SysTickError;
+pub use handle::{SysTick, TickCount};
+pub use units::{hz, khz, mhz, Hertz};
+
+use core::time::Duration;
 
 use cortex_m::interrupt;
 
 #[cfg(feature = "irq_handler")]
 use cortex_m_rt::exception;
 
+/// Maximum value of the 24-bit reload register.
+const MAX_RELOAD: u32 = 0x00FF_FFFF;
+
+/// Counter enable bit in the control and status register.
+const CSR_ENABLE: u32 = 1 << 0;
+
+/// Count flag in the control and status register.
+const CSR_COUNTFLAG: u32 = 1 << 16;
+
 /// SysTick peripheral.
 static mut SYSTICK: Option<cortex_m::peripheral::SYST> = None;
 
 /// SysTick counter increased in interrupt.
 static mut SYSTICK_COUNTER: u64 = 0;
 
-/// System clock frequency in MHz.
-static mut CLOCK_FREQ_MHZ: u32 = 0;
+/// System clock frequency in Hz.
+static mut CLOCK_FREQ: u32 = 0;
+
+/// Core clock frequency in Hz for the DWT cycle counter, 0 if unknown.
+static mut CORE_CLOCK_FREQ: u32 = 0;
 
 /// SysTick frequency in Hz.
 static mut TICK_FREQ: u32 = 0;
 
+/// Clock source of the timer, selected at init or by `set_clock_source()`.
+static mut CLOCK_SOURCE: ClockSource = ClockSource::Core;
+
+/// Tick count at init, passed to `init_with_offset()`.
+static mut INIT_TICKS: u64 = 0;
+
+/// Number of times the tick count was reset.
+static mut RESET_GENERATION: u32 = 0;
+
+/// Polling mode without interrupt enabled.
+static mut POLLING: bool = false;
+
+/// Reload value dithering for an exact average tick frequency.
+static mut DITHER: bool = false;
+
+/// Last value returned by `clock_cycles()` and its reset generation.
+#[cfg(feature = "debug_monotonic")]
+static mut LAST_CYCLES: (u64, u32) = (0, 0);
+
+/// Number of times `clock_cycles()` returned a lower value than before.
+#[cfg(feature = "debug_monotonic")]
+static mut MONOTONIC_VIOLATIONS: u32 = 0;
+
+/// A wrap was detected outside of the interrupt, which will count it.
+static mut WRAP_PENDING: bool = false;
+
+/// Time is frozen while the interrupt keeps firing for wakeup.
+static mut WAKEUP_ONLY: bool = false;
+
+/// Cycles into the tick period at which the time was frozen.
+static mut FROZEN_ELAPSED: u64 = 0;
+
+/// The interrupt didn't run for more than a tick period.
+static mut STALLED: bool = false;
+
+/// Number of detected missed tick events.
+static mut MISSED_TICKS: u32 = 0;
+
+/// Number of interrupt calls without a counter wrap.
+static mut SPURIOUS_CALLS: u32 = 0;
+
+/// Software flag set on each tick, cleared by `consume_wrap()`.
+static mut WRAP_LATCH: bool = false;
+
 /// Optional callback function triggered within SysTick interrupt
+#[cfg(not(feature = "no_callback"))]
 static mut CALLBACK_FN: Option<fn(u64)> = None;
 
+/// Callback function and handler are skipped temporarily.
+#[cfg(not(feature = "no_callback"))]
+static mut CALLBACK_SUSPENDED: bool = false;
+
+/// Optional callback function deferred to `service_deferred()`.
+#[cfg(not(feature = "no_callback"))]
+static mut DEFERRED_FN: Option<fn(u64)> = None;
+
+/// Ticks since the deferred callback function was serviced.
+#[cfg(not(feature = "no_callback"))]
+static mut DEFERRED_PENDING: u32 = 0;
+
+/// Request a PendSV exception when the deferred callback function is pending.
+#[cfg(not(feature = "no_callback"))]
+static mut DEFERRED_PENDSV: bool = false;
+
+/// Tick count passed to the callback function and handler.
+#[cfg(not(feature = "no_callback"))]
+static mut CALLBACK_TIMING: CallbackTiming = CallbackTiming::After;
+
+/// Optional handler object triggered within SysTick interrupt.
+#[cfg(not(feature = "no_callback"))]
+static mut TICK_HANDLER: Option<&'static dyn TickHandler> = None;
+
+/// Callback function took longer than the tick period.
+#[cfg(all(debug_assertions, not(feature = "no_callback")))]
+static mut CALLBACK_OVERRUN: bool = false;
+
+/// Callback function of a pending one-shot.
+static mut ONESHOT_FN: Option<fn()> = None;
+
+/// Cycles since the last counted tick when a pending one-shot was started.
+static mut ONESHOT_START: u64 = 0;
+
+/// Optional callback function triggered after reconfiguration.
+static mut RECONFIGURE_FN: Option<fn(u32, u32)> = None;
+
+/// Handler for SysTick interrupts, alternative to a callback function.
+#[cfg(not(feature = "no_callback"))]
+pub trait TickHandler: Sync {
+    /// Called on each SysTick interrupt after updating the tick count.
+    fn on_tick(&self, ticks: u64);
+}
+
+/// Tick count passed to the callback function and handler, see `set_callback_timing()`.
+#[cfg(not(feature = "no_callback"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallbackTiming {
+    /// The count before the increment, i.e. of the tick period that just ended.
+    Before,
+    /// The count after the increment, i.e. of the tick period that just started.
+    #[default]
+    After,
+}
+
+/// Clock source of the timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClockSource {
+    /// Processor core clock.
+    Core,
+    /// Implementation-defined external reference clock.
+    External,
+}
+
+/// Timer configuration and state, see `config()`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Clock frequency in Hz.
+    pub clock_hz: u32,
+    /// Tick frequency in Hz.
+    pub tick_hz: u32,
+    /// Reload value of the current tick period.
+    pub reload: u32,
+    /// Clock source of the timer.
+    pub clock_source: ClockSource,
+    /// Tick count.
+    pub counter: u64,
+    /// The counter is enabled.
+    pub running: bool,
+    /// A callback function or handler is set.
+    pub callback_set: bool,
+    /// Duration of a clock cycle in microseconds.
+    pub resolution_us: f32,
+}
+
 /// Initializes the SysTick counter with a frequency.
 ///
 /// Sets the reload value according to the desired frequency and enables the interrupt.
@@ -31,39 +196,488 @@ static mut CALLBACK_FN: Option<fn(u64)> = None;
 /// - `syst` is the peripheral and will be consumed
 /// - `clock_freq`: System core clock frequency in Hz
 /// - `tick_freq`: SysTick frequency in Hz
-pub fn init_with_frequency(mut syst: cortex_m::peripheral::SYST, clock_freq: u32, tick_freq: u32) {
-    // Make sure interrupt does not run while doing the init
-    syst.disable_interrupt();
+pub fn init_with_frequency(syst: cortex_m::peripheral::SYST, clock_freq: u32, tick_freq: u32) {
+    SysTickBuilder::new(syst)
+        .clock_hz(clock_freq)
+        .tick_hz(tick_freq)
+        .apply();
+}
+
+/// Initializes the SysTick counter with a frequency if the configuration is valid.
+///
+/// Same as `init_with_frequency()`, but returns an error instead of misconfiguring
+/// the timer. The peripheral is dropped in that case.
+pub fn try_init_with_frequency(
+    syst: cortex_m::peripheral::SYST,
+    clock_freq: u32,
+    tick_freq: u32,
+) -> Result<(), SysTickError> {
+    SysTickBuilder::new(syst)
+        .clock_hz(clock_freq)
+        .tick_hz(tick_freq)
+        .build()
+}
+
+/// Initializes the SysTick counter with a clock source if it is available.
+///
+/// Same as `try_init_with_frequency()`, but `clock_freq` is the frequency of the selected
+/// source, which is also used for `clock_cycles()` and the functions derived from it.
+/// Returns an error if the external reference clock is selected but not implemented,
+/// see `has_reference_clock()`.
+pub fn try_init_with_source(
+    syst: cortex_m::peripheral::SYST,
+    source: ClockSource,
+    clock_freq: u32,
+    tick_freq: u32,
+) -> Result<(), SysTickError> {
+    SysTickBuilder::new(syst)
+        .source(source)
+        .clock_hz(clock_freq)
+        .tick_hz(tick_freq)
+        .build()
+}
+
+/// Returns if the external reference clock is implemented.
+///
+/// Read from the NOREF bit of the calibration register. On parts without it,
+/// selecting the external clock source has no effect.
+pub fn has_reference_clock() -> bool {
+    cortex_m::peripheral::SYST::has_reference_clock()
+}
 
-    // Core clock must be used as source, otherwise calculations will be wrong
-    syst.set_clock_source(cortex_m::peripheral::syst::SystClkSource::Core);
+/// Returns if the calibration value of the timer is exact.
+///
+/// Read from the SKEW bit of the calibration register. The value is inexact if the
+/// clock frequency is not a multiple of 100Hz.
+pub fn is_calib_exact() -> bool {
+    cortex_m::peripheral::SYST::is_precise()
+}
+
+/// Returns the clock source value for the peripheral.
+fn syst_clock_source(source: ClockSource) -> cortex_m::peripheral::syst::SystClkSource {
+    match source {
+        ClockSource::Core => cortex_m::peripheral::syst::SystClkSource::Core,
+        ClockSource::External => cortex_m::peripheral::syst::SystClkSource::External,
+    }
+}
+
+/// Initializes the SysTick counter with a frequency and an initial tick count.
+///
+/// Same as `init_with_frequency()`, but the tick count starts at `initial_ticks`,
+/// e.g. the ticks elapsed in a bootloader at the same tick frequency. `ticks()` and
+/// the functions derived from it then report the uptime across both stages, while
+/// `ticks_since_init()` excludes the offset. The offset is cleared by `reset()`
+/// and `reconfigure()`, which restart the tick count at 0.
+pub fn init_with_offset(
+    syst: cortex_m::peripheral::SYST,
+    clock_freq: u32,
+    tick_freq: u32,
+    initial_ticks: u64,
+) {
+    SysTickBuilder::new(syst)
+        .clock_hz(clock_freq)
+        .tick_hz(tick_freq)
+        .offset(initial_ticks)
+        .apply();
+}
+
+/// Initializes the SysTick counter with a frequency and starts it.
+///
+/// Same as `init_with_frequency()` followed by `start()`. Use the two-step init
+/// if the counter has to be started at a precise moment.
+pub fn init_and_start(syst: cortex_m::peripheral::SYST, clock_freq: u32, tick_freq: u32) {
+    init_with_frequency(syst, clock_freq, tick_freq);
+    start();
+}
+
+/// Initializes the SysTick counter for polling mode.
+///
+/// Same as `init_with_frequency()`, but the SysTick interrupt is not enabled. Instead,
+/// counter wraps are detected on each call of `poll()`, `ticks()`, `clock_cycles()` or
+/// the functions derived from them. The callback function is called from `poll()`.
+///
+/// Wraps that are not detected are lost, so one of these functions must be called
+/// at least once per tick period (`1 / tick_freq`). Use a low tick frequency to
+/// increase the maximum safe polling interval, but note that the period is limited
+/// to 2^24 clock cycles by the reload register.
+pub fn init_polling_with_frequency(
+    syst: cortex_m::peripheral::SYST,
+    clock_freq: u32,
+    tick_freq: u32,
+) {
+    init_with_frequency(syst, clock_freq, tick_freq);
 
     unsafe {
-        // The tick counter should start with 0 after init
-        SYSTICK_COUNTER = 0;
+        SYSTICK.as_mut().unwrap().disable_interrupt();
+        POLLING = true;
+    }
+}
+
+/// Initializes the SysTick counter with typed frequencies.
+///
+/// Same as `init_with_frequency()`, but avoids mistakes in the number of zeros,
+/// e.g. `init(syst, mhz(168), khz(1))`.
+pub fn init(syst: cortex_m::peripheral::SYST, clock_freq: Hertz, tick_freq: Hertz) {
+    init_with_frequency(syst, clock_freq.to_hz(), tick_freq.to_hz());
+}
+
+/// Reconfigures the timer for new clock and tick frequencies.
+///
+/// Must be called immediately after changing the system clock at runtime.
+/// The elapsed time is converted to the new frequencies, so `millis()` and `micros()`
+/// continue from their current values. Raw tick counts and clock cycles can't be
+/// compared across the call, so the reset generation is incremented.
+/// Dithering is disabled and must be enabled again if required.
+/// The reconfigure callback is called after the new values are committed.
+pub fn reconfigure(clock_freq: u32, tick_freq: u32) {
+    reconfigure_with_source(unsafe { CLOCK_SOURCE }, clock_freq, tick_freq);
+}
+
+/// Same as `reconfigure()`, also switching the clock source while the counter is stopped.
+fn reconfigure_with_source(source: ClockSource, clock_freq: u32, tick_freq: u32) {
+    interrupt::free(|_| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+        let reload = period_reload(syst, SYSTICK_COUNTER);
+        let (elapsed, enabled) = stop_and_read(syst, reload);
+
+        // All wraps are accounted for, so the interrupt must not count them again
+        cortex_m::peripheral::SCB::clear_pendst();
 
-        // These values need to be stored for further calculations
-        CLOCK_FREQ_MHZ = clock_freq / 1000000;
+        let cycles = period_start(syst, SYSTICK_COUNTER) + elapsed;
+        let cycles = muldiv_u64(cycles, clock_freq as u64, CLOCK_FREQ as u64);
+
+        CLOCK_FREQ = clock_freq;
         TICK_FREQ = tick_freq;
+        SYSTICK_COUNTER = 0;
+        INIT_TICKS = 0;
+        DITHER = false;
+        RESET_GENERATION = RESET_GENERATION.wrapping_add(1);
+
+        CLOCK_SOURCE = source;
+        syst.set_clock_source(syst_clock_source(source));
+        if source == ClockSource::Core {
+            CORE_CLOCK_FREQ = clock_freq;
+        }
+
+        let reload = (clock_freq / tick_freq) - 1;
+        resume_periodic(syst, reload, cycles, enabled);
+    });
+
+    if let Some(callback) = unsafe { RECONFIGURE_FN } {
+        callback(clock_freq, tick_freq);
     }
+}
 
-    // Setup the timer registers with the required values
-    let reload = (clock_freq / tick_freq) - 1;
-    syst.set_reload(reload);
-    syst.clear_current();
+/// Switches the clock source at runtime.
+///
+/// `clock_freq` is the frequency of the new source in Hz. It can't be detected, so it
+/// must be supplied including any fixed prescaler, e.g. the core clock divided by 8 on
+/// parts deriving the external reference from it. Same as `reconfigure()` otherwise,
+/// so `millis()` and `micros()` continue with the new counting rate.
+/// Returns an error without changing anything if the external reference clock is not
+/// implemented or the tick frequency can't be generated from the new clock frequency.
+pub fn set_clock_source(source: ClockSource, clock_freq: u32) -> Result<(), SysTickError> {
+    if unsafe { SYSTICK.is_none() } {
+        return Err(SysTickError::NotInitialized);
+    }
+
+    if source == ClockSource::External && !has_reference_clock() {
+        return Err(SysTickError::NoReferenceClock);
+    }
+
+    check_frequencies(clock_freq, tick_freq())?;
+
+    reconfigure_with_source(source, clock_freq, tick_freq());
+    Ok(())
+}
+
+/// Returns the clock source of the timer.
+pub fn clock_source() -> ClockSource {
+    unsafe { CLOCK_SOURCE }
+}
+
+/// Changes the tick frequency while the clock frequency stays the same.
+///
+/// Same as `reconfigure()` with the stored clock frequency. Timestamps from `micros()`
+/// and `millis()` stay valid across the call, but `millis()` is only updated with the
+/// granularity of the new tick period. Raw tick counts can't be compared across the call.
+/// Returns an error without changing anything if the new tick frequency is out of range.
+pub fn set_tick_freq(tick_freq: u32) -> Result<(), SysTickError> {
+    if unsafe { SYSTICK.is_none() } {
+        return Err(SysTickError::NotInitialized);
+    }
+
+    let clock_freq = clock_freq();
+    check_frequencies(clock_freq, tick_freq)?;
+
+    reconfigure(clock_freq, tick_freq);
+    Ok(())
+}
+
+/// Runs a closure with a temporary tick frequency and restores the previous one afterwards.
+///
+/// Same as `set_tick_freq()` before and after calling `f`, e.g. for a finer tick during
+/// a short precise timing phase. `micros()` and `millis()` continue across both changes.
+/// Returns an error without calling `f` if the tick frequency is out of range.
+pub fn with_tick_freq<R>(tick_freq: u32, f: impl FnOnce() -> R) -> Result<R, SysTickError> {
+    let previous = self::tick_freq();
+    set_tick_freq(tick_freq)?;
+
+    let result = f();
+
+    // The previous frequency was valid before, so restoring it can't fail
+    let _ = set_tick_freq(previous);
+
+    Ok(result)
+}
+
+/// Checks if the timer can generate the tick frequency from the clock frequency.
+fn check_frequencies(clock_freq: u32, tick_freq: u32) -> Result<(), SysTickError> {
+    if tick_freq == 0 || tick_freq < min_tick_freq(clock_freq) {
+        Err(SysTickError::ReloadOutOfRange)
+    } else if tick_freq > max_tick_freq(clock_freq) {
+        Err(SysTickError::ClockTooLow)
+    } else {
+        Ok(())
+    }
+}
+
+/// Restores the timer configuration after a low-power mode that didn't preserve it.
+///
+/// Re-applies the reload value, the clock source and the interrupt enable from the
+/// stored clock and tick frequencies, then starts the counter. The tick count is not
+/// modified, so `millis()` and `micros()` continue from the values before entering the
+/// low-power mode. The time spent in the low-power mode as well as the progress into
+/// the tick period at that moment are lost.
+pub fn resync_after_wakeup() {
+    interrupt::free(|_| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+
+        syst.disable_counter();
+        syst.set_clock_source(syst_clock_source(CLOCK_SOURCE));
+        syst.set_reload(CLOCK_FREQ / TICK_FREQ - 1);
+        if DITHER {
+            syst.set_reload(period_reload(syst, SYSTICK_COUNTER));
+        }
+        syst.clear_current();
+
+        if POLLING {
+            syst.disable_interrupt();
+        } else {
+            syst.enable_interrupt();
+        }
+
+        syst.enable_counter();
+        if DITHER {
+            // The current period is loaded, so the reload can be set for the next one
+            syst.set_reload(period_reload(syst, SYSTICK_COUNTER + 1));
+        }
+    });
+}
+
+/// Enables dithering of the reload value for an exact average tick frequency.
+///
+/// When the clock frequency is not an integer multiple of the tick frequency, the
+/// truncated reload value makes the ticks slightly too fast. With dithering enabled,
+/// the interrupt extends single tick periods by one cycle whenever the accumulated
+/// remainder exceeds a full cycle, so the average tick frequency matches exactly.
+///
+/// The tick count is reset, so this should be called after init and before `start()`.
+/// Dithering is disabled by init, `reconfigure()` and `tickless_sleep_until()`.
+pub fn enable_dithering() {
+    unsafe { DITHER = true };
+    reset();
+}
+
+/// Returns the number of clock cycles at the start of the tick period after `ticks` ticks.
+unsafe fn period_start(syst: &cortex_m::peripheral::SYST, ticks: u64) -> u64 {
+    if ONESHOT_FN.is_some() {
+        // The current period is the one-shot interval
+        return (CLOCK_FREQ / TICK_FREQ) as u64 * ticks + ONESHOT_START;
+    }
+
+    if !DITHER {
+        return (syst.rvr.read() as u64 + 1) * ticks;
+    }
+
+    dithered_period_start(CLOCK_FREQ, TICK_FREQ, ticks)
+}
+
+/// Returns the reload value of the tick period after `ticks` ticks.
+unsafe fn period_reload(syst: &cortex_m::peripheral::SYST, ticks: u64) -> u32 {
+    if !DITHER || ONESHOT_FN.is_some() {
+        return syst.rvr.read();
+    }
+
+    dithered_period_reload(CLOCK_FREQ, TICK_FREQ, ticks)
+}
+
+/// Returns the number of clock cycles at the start of the tick period after `ticks` ticks
+/// with dithering enabled.
+const fn dithered_period_start(clock_freq: u32, tick_freq: u32, ticks: u64) -> u64 {
+    // The first two periods are never extended, because the period after the
+    // current one is already loaded when the interrupt sets the reload value
+    let cycles = (clock_freq / tick_freq) as u64 * ticks;
+    let remainder = (clock_freq % tick_freq) as u128 * ticks.saturating_sub(1) as u128;
+    cycles + (remainder / tick_freq as u128) as u64
+}
+
+/// Returns the reload value of the tick period after `ticks` ticks with dithering enabled.
+const fn dithered_period_reload(clock_freq: u32, tick_freq: u32, ticks: u64) -> u32 {
+    let start = dithered_period_start(clock_freq, tick_freq, ticks);
+    (dithered_period_start(clock_freq, tick_freq, ticks + 1) - start - 1) as u32
+}
+
+/// Returns the frequency of the clock source in Hz.
+pub fn clock_freq() -> u32 {
+    unsafe { CLOCK_FREQ }
+}
+
+/// Returns the core clock frequency in Hz used with the DWT cycle counter, 0 if unknown.
+///
+/// Same as `clock_freq()` with the core clock source. With the external clock source,
+/// it's only known if set with `set_core_clock_freq()`.
+pub fn core_clock_freq() -> u32 {
+    unsafe { CORE_CLOCK_FREQ }
+}
+
+/// Sets the core clock frequency in Hz when the timer runs on the external clock source.
+///
+/// Used by the functions reading the DWT cycle counter, which counts core clock cycles
+/// independently of the clock source of the timer. Must be called again after changing
+/// the core clock. The value is overwritten by init and `reconfigure()` with the core
+/// clock source, and cleared by init with the external clock source.
+pub fn set_core_clock_freq(freq: u32) {
+    unsafe { CORE_CLOCK_FREQ = freq };
+}
+
+/// Returns the SysTick frequency in Hz.
+pub fn tick_freq() -> u32 {
+    unsafe { TICK_FREQ }
+}
+
+/// Measures the apparent tick frequency in Hz over a window of milliseconds.
+///
+/// Counts the ticks between two tick boundaries at least `window_ms` apart and relates
+/// them to the clock cycles in between, measured with the DWT cycle counter if it is
+/// enabled and the core clock frequency is known, otherwise with `clock_cycles()`.
+/// A result diverging from `tick_freq()` indicates a misconfiguration, e.g. a wrong
+/// clock source or a handler counting ticks twice. The reference is assumed to be
+/// trustworthy, so a wrong clock frequency passed to init that is used consistently
+/// everywhere is not detected.
+/// Returns 0 if no tick occurs within the window or two tick periods. The window is
+/// limited to 2^32 cycles.
+pub fn measured_tick_hz(window_ms: u32) -> u32 {
+    #[cfg(not(armv6m))]
+    let dwt = cortex_m::peripheral::DWT::cycle_counter_enabled() && core_clock_freq() != 0;
+    #[cfg(armv6m)]
+    let dwt = false;
+
+    let reference_hz = if dwt { core_clock_freq() } else { clock_freq() };
+    let window = muldiv_u64(window_ms as u64, reference_hz as u64, 1000).min(u32::MAX as u64);
+    let window = window as u32;
+    let timeout = window.max((reference_hz / tick_freq()).saturating_mul(2));
+
+    let reference = || -> u32 {
+        #[cfg(not(armv6m))]
+        if dwt {
+            return cortex_m::peripheral::DWT::cycle_count();
+        }
+
+        clock_cycles() as u32
+    };
+
+    // Waits for the next tick, returning its count and the reference cycles
+    let next_tick = |timeout: u32| -> Option<(u64, u32)> {
+        let begin = reference();
+        let last = ticks();
+
+        loop {
+            let ticks = ticks();
+            let now = reference();
+            if ticks != last {
+                return Some((ticks, now));
+            }
+            if now.wrapping_sub(begin) > timeout {
+                return None;
+            }
+        }
+    };
+
+    let Some((start_ticks, start)) = next_tick(timeout) else {
+        return 0;
+    };
 
-    // Finally start the interrupt and let everything run
-    syst.enable_interrupt();
+    while reference().wrapping_sub(start) < window {}
 
-    unsafe { SYSTICK = Some(syst) }
+    let Some((end_ticks, end)) = next_tick(timeout) else {
+        return 0;
+    };
+
+    let cycles = end.wrapping_sub(start) as u64;
+    muldiv_u64(end_ticks - start_ticks, reference_hz as u64, cycles.max(1)) as u32
+}
+
+/// Returns the highest tick frequency in Hz that can be used with a clock frequency.
+///
+/// The reload value must be at least 1, so the counter needs a minimum of 2 cycles per tick.
+pub const fn max_tick_freq(clock_freq: u32) -> u32 {
+    clock_freq / 2
+}
+
+/// Returns the lowest tick frequency in Hz that can be used with a clock frequency.
+///
+/// Lower frequencies would require a reload value exceeding the 24-bit register.
+pub const fn min_tick_freq(clock_freq: u32) -> u32 {
+    (clock_freq as u64 / (MAX_RELOAD as u64 + 2)) as u32 + 1
+}
+
+/// Returns the lowest clock frequency in Hz for a clock cycle resolution in nanoseconds.
+///
+/// The clock frequency must also be at least twice the tick frequency. A higher clock
+/// frequency gives a larger reload value, which must fit into the 24-bit register,
+/// so the feasible combinations are bounded by `min_tick_freq()`.
+/// Returns 0 if no clock frequency satisfies all constraints.
+pub const fn required_clock_hz(tick_freq: u32, target_resolution_ns: u32) -> u32 {
+    if tick_freq == 0 || target_resolution_ns == 0 {
+        return 0;
+    }
+
+    let clock_freq = 1_000_000_000u64.div_ceil(target_resolution_ns as u64);
+    let clock_freq = if clock_freq < tick_freq as u64 * 2 {
+        tick_freq as u64 * 2
+    } else {
+        clock_freq
+    };
+
+    if clock_freq > u32::MAX as u64 || tick_freq < min_tick_freq(clock_freq as u32) {
+        return 0;
+    }
+
+    clock_freq as u32
 }
 
 /// Returns the SysTick timer.
 ///
 /// Use this function to get back ownership of the peripheral.
-/// No prior actions like `stop()` are performed by this function.
+/// No prior actions like `stop()` are performed by this function, but the callback
+/// function, handler and a pending one-shot are cleared, so a spurious interrupt
+/// afterwards can't call them. Frozen time and polling mode are left as well.
 pub fn free() -> cortex_m::peripheral::SYST {
-    unsafe { SYSTICK.take().unwrap() }
+    interrupt::free(|_| unsafe {
+        #[cfg(not(feature = "no_callback"))]
+        {
+            CALLBACK_FN = None;
+            TICK_HANDLER = None;
+            DEFERRED_FN = None;
+        }
+        ONESHOT_FN = None;
+        ONESHOT_START = 0;
+        POLLING = false;
+        WAKEUP_ONLY = false;
+        FROZEN_ELAPSED = 0;
+        SYSTICK.take().unwrap()
+    })
 }
 
 /// Starts the counter.
@@ -71,94 +685,1192 @@ pub fn free() -> cortex_m::peripheral::SYST {
 /// Initialisation must be done before calling this function.
 /// Use `stop()` to halt the counter again.
 pub fn start() {
-    unsafe { SYSTICK.as_mut().unwrap().enable_counter() }
+    interrupt::free(|_| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+        syst.csr.write(read_csr(syst) | CSR_ENABLE);
+    });
+}
+
+/// Starts the counter if the timer is initialized.
+pub fn try_start() -> Result<(), SysTickError> {
+    if unsafe { SYSTICK.is_none() } {
+        return Err(SysTickError::NotInitialized);
+    }
+
+    start();
+    Ok(())
 }
 
 /// Stops the counter.
 pub fn stop() {
-    unsafe { SYSTICK.as_mut().unwrap().disable_counter() }
+    interrupt::free(|_| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+        syst.csr.write(read_csr(syst) & !CSR_ENABLE);
+    });
+}
+
+/// Stops the counter until the returned guard is dropped.
+///
+/// The tick count and the progress into the current tick period are preserved, so
+/// the time spent in the scope is not counted. On drop, the counter is only started
+/// again if it was running before, so guards can be nested.
+pub fn pause_scoped() -> PauseGuard {
+    let running = interrupt::free(|_| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+
+        // Reading the status register clears COUNTFLAG, so it must be read only once
+        let csr = read_csr(syst);
+        syst.csr.write(csr & !CSR_ENABLE);
+
+        (csr & CSR_ENABLE) != 0
+    });
+
+    PauseGuard { running }
+}
+
+/// Guard returned by `pause_scoped()`, restores the counter state when dropped.
+#[derive(Debug)]
+#[must_use = "the counter is resumed immediately if the guard is not held"]
+pub struct PauseGuard {
+    /// The counter was running before pausing.
+    running: bool,
+}
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        if self.running {
+            start();
+        }
+    }
 }
 
 /// Resets the counter.
 pub fn reset() {
     interrupt::free(|_| unsafe {
-        SYSTICK.as_mut().unwrap().clear_current();
-        SYSTICK_COUNTER = 0
+        let syst = SYSTICK.as_mut().unwrap();
+        if DITHER {
+            // The period must start with the reload value for a tick count of 0
+            syst.set_reload(CLOCK_FREQ / TICK_FREQ - 1);
+        }
+        syst.clear_current();
+        WRAP_PENDING = false;
+        SYSTICK_COUNTER = 0;
+        INIT_TICKS = 0;
+        RESET_GENERATION = RESET_GENERATION.wrapping_add(1);
+    });
+}
+
+/// Sets the tick count.
+///
+/// Can be used to restore the uptime after a warm reset with retained RAM or a
+/// backup register. The tick count jumps to the new value, so time is no longer
+/// strictly monotonic and timestamps taken before can't be compared to new ones.
+/// The reset generation is incremented for that reason.
+pub fn set_ticks(value: u64) {
+    interrupt::free(|_| unsafe {
+        SYSTICK_COUNTER = value;
+        RESET_GENERATION = RESET_GENERATION.wrapping_add(1);
     });
 }
 
-/// Returns the tick count.
+/// Passes the tick count to a store function, e.g. writing it to a backup register.
+///
+/// Call this before a planned reset and `restore_from()` after it to preserve
+/// the uptime. The count is lost on resets without a prior call.
+pub fn persist_to(store: &mut dyn FnMut(u64)) {
+    store(ticks());
+}
+
+/// Restores a tick count saved with `persist_to()`.
+///
+/// Must be called after init. Same as `set_ticks()`.
+pub fn restore_from(value: u64) {
+    set_ticks(value);
+}
+
+/// Returns the reset generation.
+///
+/// The value is incremented each time the tick count is reset by `init_with_frequency()`,
+/// `reset()`, `set_ticks()` or `reconfigure()`. Timestamps taken in different
+/// generations can't be compared.
+pub fn generation() -> u32 {
+    unsafe { RESET_GENERATION }
+}
+
+/// Returns the tick count, including the offset passed to `init_with_offset()`.
 pub fn ticks() -> u64 {
-    interrupt::free(|_| unsafe { SYSTICK_COUNTER })
+    poll();
+    critical_section(|| unsafe { SYSTICK_COUNTER })
+}
+
+/// Returns the tick count since init, excluding the offset passed to `init_with_offset()`.
+pub fn ticks_since_init() -> u64 {
+    poll();
+    critical_section(|| unsafe { SYSTICK_COUNTER.saturating_sub(INIT_TICKS) })
 }
 
 /// Returns the number of core clock cycles.
 pub fn clock_cycles() -> u64 {
-    interrupt::free(|_| {
-        let mut ticks = unsafe { SYSTICK_COUNTER };
-        let syst = unsafe { SYSTICK.as_mut().unwrap() };
-        let load = syst.rvr.read();
-        let val = syst.cvr.read();
+    poll();
 
-        if syst.has_wrapped() {
-            // This catches the case when the counter has reached 0 after
-            // the last interrupt but before reading the value in the
-            // statement above.
-            ticks += 1;
-        }
+    critical_section(|| unsafe {
+        let snapshot = snapshot();
+        let cycles = snapshot.start + snapshot.elapsed() as u64;
 
-        ((load as u64 + 1) * ticks) + (load - val) as u64
+        #[cfg(feature = "debug_monotonic")]
+        check_monotonic(cycles);
+
+        cycles
     })
 }
 
-/// Returns elapsed milliseconds.
-pub fn millis() -> u64 {
-    unsafe { ticks() * 1000 / TICK_FREQ as u64 }
+/// Returns the tick count and the fraction of the current tick period from 0.0 to 1.0.
+///
+/// Both values are read consistently, so the fraction never reaches 1.0 when
+/// the counter wraps in between.
+pub fn tick_fraction() -> (u64, f32) {
+    poll();
+
+    critical_section(|| unsafe {
+        let snapshot = snapshot();
+        let fraction = snapshot.elapsed() as f32 / (snapshot.reload as f32 + 1.0);
+
+        (snapshot.ticks, fraction)
+    })
 }
 
-/// Returns elapsed microseconds.
-pub fn micros() -> u64 {
-    unsafe { clock_cycles() / CLOCK_FREQ_MHZ as u64 }
+/// Freezes the time while keeping the periodic interrupt as a wakeup source.
+///
+/// Intended for idle strategies where the time is maintained by another source, e.g.
+/// an RTC, while the core is parked in WFI. While enabled, the interrupt still fires
+/// once per tick period, but neither counts the tick nor calls the callback function,
+/// so `ticks()`, `millis()`, `micros()` and the functions derived from them return the
+/// value from the moment of enabling. After disabling, the time continues from there,
+/// use `set_ticks()` to apply the time from the other source. Dithering is disabled.
+pub fn wakeup_only_mode(enable: bool) {
+    interrupt::free(|_| unsafe {
+        if enable == WAKEUP_ONLY {
+            return;
+        }
+
+        let syst = SYSTICK.as_mut().unwrap();
+        let (elapsed, enabled) = stop_and_read(syst, period_reload(syst, SYSTICK_COUNTER));
+
+        // All wraps are accounted for, so the interrupt must not count them again
+        cortex_m::peripheral::SCB::clear_pendst();
+
+        let reload = CLOCK_FREQ / TICK_FREQ - 1;
+        DITHER = false;
+
+        if enable {
+            // Commit the full periods and keep the progress into the current one
+            let period = reload as u64 + 1;
+            SYSTICK_COUNTER += elapsed / period;
+            FROZEN_ELAPSED = elapsed % period;
+            WAKEUP_ONLY = true;
+
+            syst.set_reload(reload);
+            syst.clear_current();
+            if enabled {
+                syst.enable_counter();
+            }
+        } else {
+            // The time spent in the mode is discarded
+            WAKEUP_ONLY = false;
+            resume_periodic(syst, reload, FROZEN_ELAPSED, enabled);
+        }
+    });
 }
 
-/// Set an interrupt callback function.
+/// Returns the clock cycles remaining until the next tick.
 ///
-/// The provided callback function is called on each SysTick interrupt
-/// after updating the tick count and passed its value as argument
-pub fn set_callback(callback: fn(u64)) {
-    unsafe {
-        CALLBACK_FN = Some(callback);
-    };
+/// Intended for timing-critical code that should start right after a tick, so the
+/// next interrupt is as far away as possible, e.g.
+/// `while cycles_until_next_tick() < threshold {}`.
+/// A wrap that happens while reading is taken into account, so the value always
+/// belongs to the current tick period.
+pub fn cycles_until_next_tick() -> u32 {
+    poll();
+
+    critical_section(|| unsafe { snapshot().current })
 }
 
-/// Clear the interrupt callback function.
-pub fn clear_callback() {
-    unsafe {
-        CALLBACK_FN = None;
+/// Returns if an operation of a number of clock cycles completes before the next tick.
+///
+/// Intended for short sequences that should not be interrupted by the SysTick interrupt,
+/// e.g. `if fits_before_next_tick(800) { do_it() }`. The latency of reading the counter
+/// is measured and added to `cycles`, so the check is conservative. Other interrupts
+/// are not taken into account.
+pub fn fits_before_next_tick(cycles: u32) -> bool {
+    let first = cycles_until_next_tick();
+    let second = cycles_until_next_tick();
+
+    let (latency, remaining) = if second <= first {
+        (first - second, second)
+    } else {
+        // The counter wrapped in between, so measure again in the new period
+        let third = cycles_until_next_tick();
+        (second.saturating_sub(third), third)
     };
+
+    remaining > cycles.saturating_add(latency)
 }
 
-/// External interrupt call.
+/// Returns the clock cycles since a counter value, given the number of wraps in between.
 ///
-/// This function must be called from the external SysTick handler
-/// when the `irq_handler` feature is disabled.
+/// `start` is a value from `cycles_until_next_tick()` and `observed_wraps` is the number
+/// of times the counter reached 0 since then, counted by the caller, e.g. in a custom
+/// long-interval timer in polling mode. This is the same accounting `clock_cycles()`
+/// does with the wraps counted by the interrupt or `poll()`. Dithering is not taken
+/// into account, all wraps are assumed to have the current reload value.
+pub fn elapsed_cycles_since(start: u32, observed_wraps: u32) -> u64 {
+    let (reload, current) = critical_section(|| unsafe {
+        let syst = SYSTICK.as_ref().unwrap();
+        (syst.rvr.read(), syst.cvr.read())
+    });
+
+    cycles_since(start, observed_wraps, reload, current)
+}
+
+/// Returns the clock cycles from counter value `start` to `current` with `wraps` in between.
+const fn cycles_since(start: u32, wraps: u32, reload: u32, current: u32) -> u64 {
+    (wraps as u64 * (reload as u64 + 1) + start as u64).saturating_sub(current as u64)
+}
+
+/// Adds the ticks missed while the interrupts were disabled to the tick count.
+///
+/// `start` is the value of `cycles_until_next_tick()` before disabling the interrupts
+/// for a number of cycles. Only one of the wraps in between is counted later by the
+/// interrupt or `poll()`, so the others are added here. Nothing is added while the
+/// counter is stopped, the time is frozen or a one-shot is pending. Must be called
+/// before the interrupts are enabled again.
+pub(crate) fn reconcile_masked_ticks(start: u32, cycles: u64) {
+    unsafe {
+        let Some(syst) = SYSTICK.as_ref() else {
+            return;
+        };
+
+        // Reading the status register latches a wrap during the delay
+        let latched = WRAP_PENDING;
+
+        // No ticks were missed with the counter stopped or the time frozen
+        if ONESHOT_FN.is_some() || WAKEUP_ONLY || (read_csr(syst) & CSR_ENABLE) == 0 {
+            return;
+        }
+
+        let period = syst.rvr.read() + 1;
+        let ticks = masked_ticks(start, cycles, period, latched);
+        if ticks > 0 {
+            SYSTICK_COUNTER += ticks;
+            MISSED_TICKS = MISSED_TICKS.saturating_add(1);
+        }
+    }
+}
+
+/// Returns the ticks to add after `cycles` with the interrupt masked, starting
+/// `start` cycles before the next wrap.
+///
+/// The pending interrupt counts a single wrap afterwards. If a wrap was already
+/// latched when masking started, that's the one it counts, so all wraps during
+/// the delay are added.
+const fn masked_ticks(start: u32, cycles: u64, period: u32, latched: bool) -> u64 {
+    let wraps = if cycles > start as u64 {
+        1 + (cycles - start as u64 - 1) / period as u64
+    } else {
+        0
+    };
+
+    if latched {
+        wraps
+    } else {
+        wraps.saturating_sub(1)
+    }
+}
+
+/// Returns the time remaining until the next tick.
+///
+/// Same as `cycles_until_next_tick()` converted to a duration, e.g. to check if
+/// an operation that must not be interrupted fits before the next tick.
+pub fn time_until_next_tick() -> Duration {
+    let cycles = cycles_until_next_tick();
+    Duration::from_nanos(muldiv_u64(cycles as u64, 1000000000, clock_freq() as u64))
+}
+
+/// Returns the timer configuration and state for diagnostics.
+pub fn config() -> Config {
+    poll();
+
+    critical_section(|| unsafe {
+        let snapshot = snapshot();
+
+        Config {
+            clock_hz: CLOCK_FREQ,
+            tick_hz: TICK_FREQ,
+            reload: snapshot.reload,
+            clock_source: CLOCK_SOURCE,
+            counter: snapshot.ticks,
+            running: snapshot.running,
+            #[cfg(not(feature = "no_callback"))]
+            callback_set: CALLBACK_FN.is_some() || TICK_HANDLER.is_some(),
+            #[cfg(feature = "no_callback")]
+            callback_set: false,
+            resolution_us: 1_000_000.0 / CLOCK_FREQ as f32,
+        }
+    })
+}
+
+/// Consistent view of the counter state.
+struct Snapshot {
+    /// Tick count, including a wrap not counted by the interrupt yet.
+    ticks: u64,
+    /// The counter is enabled.
+    running: bool,
+    /// Clock cycles at the start of the current tick period.
+    start: u64,
+    /// Reload value of the current tick period.
+    reload: u32,
+    /// Current value of the counter.
+    current: u32,
+}
+
+impl Snapshot {
+    /// Returns the cycles elapsed in the current tick period.
+    fn elapsed(&self) -> u32 {
+        self.reload - self.current
+    }
+}
+
+/// Reads the status register outside of the interrupt.
+///
+/// Reading clears COUNTFLAG, so a wrap detected here is latched for the pending
+/// interrupt, which would take its call for a spurious one otherwise, or for the
+/// next call of `poll()` in polling mode.
+unsafe fn read_csr(syst: &cortex_m::peripheral::SYST) -> u32 {
+    let csr = syst.csr.read();
+
+    if (csr & CSR_COUNTFLAG) != 0 {
+        if WRAP_PENDING && !POLLING {
+            // The interrupt didn't count the wrap detected before
+            STALLED = true;
+            MISSED_TICKS = MISSED_TICKS.saturating_add(1);
+        }
+        WRAP_PENDING = true;
+    }
+
+    csr
+}
+
+/// Reads the counter state. Must be called in a critical section.
+unsafe fn snapshot() -> Snapshot {
+    let ticks = SYSTICK_COUNTER;
+    let syst = SYSTICK.as_mut().unwrap();
+    let current = syst.cvr.read();
+
+    // Reading the status register clears COUNTFLAG, so it must be read only once
+    let csr = read_csr(syst);
+
+    let (ticks, current) = if WAKEUP_ONLY {
+        // The counter only runs for the wakeup, the time stays where it was frozen
+        (ticks, syst.rvr.read() - FROZEN_ELAPSED as u32)
+    } else {
+        resolve_counter(
+            ticks,
+            csr,
+            WRAP_PENDING,
+            current,
+            || syst.cvr.read(),
+            || period_reload(syst, ticks),
+        )
+    };
+
+    Snapshot {
+        ticks,
+        running: (csr & CSR_ENABLE) != 0,
+        start: period_start(syst, ticks),
+        reload: period_reload(syst, ticks),
+        current,
+    }
+}
+
+/// Returns the tick count and the current value of the counter for `snapshot()`.
+///
+/// `current` is the value read before the status register `csr`, `reread` reads it again.
+/// `pending` is set if a wrap was detected that is not counted yet. `reload` returns the
+/// reload value of the period.
+fn resolve_counter(
+    ticks: u64,
+    csr: u32,
+    pending: bool,
+    current: u32,
+    reread: impl FnOnce() -> u32,
+    reload: impl FnOnce() -> u32,
+) -> (u64, u32) {
+    if (csr & CSR_ENABLE) == 0 && current == 0 {
+        // The current value was cleared by init or `reset()` and the counter
+        // was not started yet, so no time has elapsed in this period.
+        (ticks, reload())
+    } else if pending {
+        // This catches the case when the counter has reached 0 after
+        // the last interrupt but before reading the current value.
+        // The wrap is counted later by the interrupt or `poll()`,
+        // so the callback function is called as usual.
+        if (csr & CSR_COUNTFLAG) != 0 {
+            // The counter may have wrapped after reading the current value,
+            // which would then belong to the previous period
+            (ticks + 1, reread())
+        } else {
+            (ticks + 1, current)
+        }
+    } else {
+        (ticks, current)
+    }
+}
+
+/// Checks that the clock cycles did not decrease since the last call.
+///
+/// Values from different reset generations are not compared.
+#[cfg(feature = "debug_monotonic")]
+unsafe fn check_monotonic(cycles: u64) {
+    let (last, generation) = LAST_CYCLES;
+
+    if generation == RESET_GENERATION && cycles < last {
+        MONOTONIC_VIOLATIONS = MONOTONIC_VIOLATIONS.saturating_add(1);
+
+        #[cfg(feature = "debug_monotonic_panic")]
+        debug_assert!(false, "clock cycles decreased from {} to {}", last, cycles);
+    }
+
+    LAST_CYCLES = (cycles, RESET_GENERATION);
+}
+
+/// Returns the number of times `clock_cycles()` returned a lower value than before.
+///
+/// Only available with the `debug_monotonic` feature. With the `debug_monotonic_panic`
+/// feature, a violation also panics in debug builds.
+#[cfg(feature = "debug_monotonic")]
+pub fn monotonic_violations() -> u32 {
+    unsafe { MONOTONIC_VIOLATIONS }
+}
+
+/// Returns if a tick occurred since the last call and clears the flag.
+///
+/// The flag is set by the interrupt, independently of the hardware COUNTFLAG
+/// which is used internally by `clock_cycles()`. So it can be used to detect ticks
+/// without a callback function and without disturbing the time calculations.
+/// Multiple ticks since the last call are reported only once.
+pub fn consume_wrap() -> bool {
+    critical_section(|| unsafe { core::mem::replace(&mut WRAP_LATCH, false) })
+}
+
+/// Returns elapsed milliseconds.
+pub fn millis() -> u64 {
+    unsafe { muldiv_u64(ticks(), 1000, TICK_FREQ as u64) }
+}
+
+/// Returns elapsed microseconds.
+///
+/// The conversion is done with a 128-bit intermediate product, so the result is exact
+/// for the full range of the 64-bit clock cycle count, which is thousands of years.
+pub fn micros() -> u64 {
+    unsafe { muldiv_u64(clock_cycles(), 1000000, CLOCK_FREQ as u64) }
+}
+
+/// Returns the resolution of `micros()` in microseconds.
+///
+/// Calculated as `ceil(1000000 / clock_freq)`, so it's 1 for clock
+/// frequencies of 1MHz and above.
+pub fn micros_resolution() -> u32 {
+    1000000u32.div_ceil(unsafe { CLOCK_FREQ }).max(1)
+}
+
+/// Returns elapsed nanoseconds.
+///
+/// Same as `micros()`, the range is only limited by the 64-bit result,
+/// which overflows after about 584 years.
+pub fn nanos() -> u64 {
+    unsafe { muldiv_u64(clock_cycles(), 1000000000, CLOCK_FREQ as u64) }
+}
+
+/// Returns `value * num / den` without overflow of the intermediate product.
+///
+/// The result is truncated toward zero. If it doesn't fit into 64 bits,
+/// the upper bits are discarded.
+pub const fn muldiv_u64(value: u64, num: u64, den: u64) -> u64 {
+    (value as u128 * num as u128 / den as u128) as u64
+}
+
+/// Converts microseconds into counts of a timescale with a frequency in Hz,
+/// e.g. 32768 for a 1/32768s protocol clock.
+///
+/// The result is truncated, so converting back with `from_timescale()` returns
+/// a value up to one count of the timescale less than the original.
+pub const fn to_timescale(value_micros: u64, target_hz: u32) -> u64 {
+    muldiv_u64(value_micros, target_hz as u64, 1000000)
+}
+
+/// Converts counts of a timescale with a frequency in Hz into microseconds.
+///
+/// The result is truncated to full microseconds.
+pub const fn from_timescale(value: u64, source_hz: u32) -> u64 {
+    muldiv_u64(value, 1000000, source_hz as u64)
+}
+
+/// Returns elapsed microseconds with low overhead.
+///
+/// Reads the DWT cycle counter directly without a critical section, which makes it
+/// suitable for high-rate event timestamps. Unlike `micros()`, the value is derived
+/// from the 32-bit cycle counter only and is not compensated for time skipped by
+/// `tickless_sleep_until()`.
+///
+/// The value restarts from 0 when the cycle counter wraps around every 2^32 core clock
+/// cycles, which is not at a power of two in microseconds, e.g. after 25565281 µs at
+/// 168 MHz. Differences of two values across that point are invalid, so this function
+/// is only suitable for intervals shorter than the wrap period, and the caller has to
+/// discard a difference that comes out larger than that.
+///
+/// The cycle counter must be enabled via `DCB::enable_trace()` and
+/// `DWT::enable_cycle_counter()`. If it is not (or not available at all, like on
+/// Cortex-M0/M0+), or the core clock frequency is unknown, see `core_clock_freq()`,
+/// the value of `micros()` truncated to 32 bits is returned instead, which wraps
+/// around every 2^32 µs.
+pub fn micros_fast() -> u32 {
+    #[cfg(not(armv6m))]
+    if cortex_m::peripheral::DWT::cycle_counter_enabled() {
+        let core_clock_freq = core_clock_freq();
+        if core_clock_freq != 0 {
+            let cycles = cortex_m::peripheral::DWT::cycle_count() as u64;
+            return (cycles * 1000000 / core_clock_freq as u64) as u32;
+        }
+    }
+
+    micros() as u32
+}
+
+/// Runs an operation a number of times and returns the average clock cycles per run.
+///
+/// The overhead of reading the clock cycles is measured and subtracted from the total.
+/// The result is wall-clock time, so it includes any interrupts firing during the runs.
+/// Returns 0 if `iters` is 0.
+pub fn bench(iters: u32, mut op: impl FnMut()) -> u64 {
+    if iters == 0 {
+        return 0;
+    }
+
+    let start = clock_cycles();
+    let overhead = clock_cycles() - start;
+
+    let start = clock_cycles();
+    for _ in 0..iters {
+        op();
+    }
+    let total = clock_cycles() - start;
+
+    total.saturating_sub(overhead) / iters as u64
+}
+
+/// Sleeps until a deadline in milliseconds without the periodic tick interrupt.
+///
+/// The reload value is reprogrammed for a single long interval ending at the deadline,
+/// the core waits for an interrupt and the normal reload is restored on wake.
+/// The tick count is advanced by the slept duration, so `millis()` and `micros()`
+/// continue as if the timer was ticking all the time. The callback function is not
+/// called for the skipped ticks. Dithering is disabled.
+///
+/// The interval is bounded by the 24-bit reload value and the function returns early
+/// when any other interrupt wakes up the core, so it should be called in a loop until
+/// the deadline is reached. The counter is stopped for a few cycles while being
+/// reprogrammed, which is not accounted for.
+///
+/// Returns immediately if the counter is stopped, or in polling mode where there's
+/// no SysTick interrupt to wake up the core.
+pub fn tickless_sleep_until(deadline_ms: u64) {
+    interrupt::free(|_| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+        if POLLING || (read_csr(syst) & CSR_ENABLE) == 0 {
+            return;
+        }
+
+        let (mut elapsed, _) = stop_and_read(syst, period_reload(syst, SYSTICK_COUNTER));
+
+        let now = period_start(syst, SYSTICK_COUNTER) + elapsed;
+        let deadline = muldiv_u64(deadline_ms, CLOCK_FREQ as u64, 1000);
+        let sleep = deadline.saturating_sub(now).min(MAX_RELOAD as u64 + 1) as u32;
+
+        if sleep >= 2 {
+            syst.set_reload(sleep - 1);
+            syst.clear_current();
+            syst.enable_counter();
+
+            cortex_m::asm::wfi();
+
+            let (slept, _) = stop_and_read(syst, sleep - 1);
+            elapsed += slept;
+        }
+
+        // All wraps are accounted for, so the interrupt must not count them again
+        cortex_m::peripheral::SCB::clear_pendst();
+
+        if DITHER {
+            // Dithered periods can't be resumed, so continue from the total cycles
+            elapsed += period_start(syst, SYSTICK_COUNTER);
+            SYSTICK_COUNTER = 0;
+            DITHER = false;
+        }
+
+        resume_periodic(syst, CLOCK_FREQ / TICK_FREQ - 1, elapsed, true);
+    });
+}
+
+/// Calls a function once after a precise number of microseconds.
+///
+/// The reload value is temporarily reprogrammed, so the SysTick interrupt fires exactly
+/// after the interval and `callback` is called from it. The periodic tick is suspended
+/// meanwhile. When the one-shot fires, the whole interval is accounted for in the
+/// tick count and the periodic tick is resumed with its original phase, so `millis()`
+/// and `micros()` stay continuous. The callback function is not called for the ticks
+/// covered by the interval. Dithering is disabled.
+///
+/// The interval is limited to 2^24 clock cycles by the reload register, longer values
+/// are clamped. A pending one-shot is replaced by a new call. `tickless_sleep_until()`,
+/// `wakeup_only_mode()` and `reconfigure()` must not be called while a one-shot is
+/// pending.
+///
+/// Returns immediately without calling `callback` if the counter is stopped.
+pub fn oneshot_us(us: u32, callback: fn()) {
+    interrupt::free(|_| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+        if (read_csr(syst) & CSR_ENABLE) == 0 {
+            return;
+        }
+
+        let (mut elapsed, _) = stop_and_read(syst, period_reload(syst, SYSTICK_COUNTER));
+
+        // All wraps are accounted for, so the interrupt must not count them again
+        cortex_m::peripheral::SCB::clear_pendst();
+
+        if ONESHOT_FN.is_some() {
+            // Continue from the start of the replaced one-shot
+            elapsed += ONESHOT_START;
+        } else if DITHER {
+            // Dithered periods can't be resumed, so continue from the total cycles
+            elapsed += period_start(syst, SYSTICK_COUNTER);
+            SYSTICK_COUNTER = 0;
+            DITHER = false;
+        }
+
+        let cycles = muldiv_u64(us as u64, CLOCK_FREQ as u64, 1000000);
+        let cycles = cycles.clamp(2, MAX_RELOAD as u64 + 1) as u32;
+
+        ONESHOT_FN = Some(callback);
+        ONESHOT_START = elapsed;
+
+        syst.set_reload(cycles - 1);
+        syst.clear_current();
+        syst.enable_counter();
+    });
+}
+
+/// Stops the counter and returns the cycles elapsed since the last counted tick.
+///
+/// A wrap that was not counted by the interrupt yet is included.
+/// Also returns if the counter was enabled before.
+unsafe fn stop_and_read(syst: &mut cortex_m::peripheral::SYST, reload: u32) -> (u64, bool) {
+    // Reading the status register clears COUNTFLAG, so the value read before
+    // disabling the counter must be taken into account
+    let csr = syst.csr.read();
+    syst.csr.write(csr & !CSR_ENABLE);
+
+    // A wrap latched by `read_csr()` before was not counted by the interrupt either.
+    // The wraps are accounted for here, the callers clear the pending interrupt.
+    let mut wraps = core::mem::replace(&mut WRAP_PENDING, false) as u64;
+    if (csr & CSR_COUNTFLAG) != 0 || syst.has_wrapped() {
+        wraps += 1;
+    }
+
+    let elapsed = (reload - syst.cvr.read()) as u64 + wraps * (reload as u64 + 1);
+
+    (elapsed, (csr & CSR_ENABLE) != 0)
+}
+
+/// Returns the full tick periods in a number of cycles since the last counted tick and
+/// the reload value of the shortened first period preserving the tick phase.
+const fn split_periods(reload: u32, elapsed: u64) -> (u64, u32) {
+    let period = reload as u64 + 1;
+    let first = reload - (elapsed % period) as u32;
+
+    if first == 0 {
+        // A reload value of 0 never triggers the interrupt, so count the tick right away
+        (elapsed / period + 1, reload)
+    } else {
+        (elapsed / period, first)
+    }
+}
+
+/// Restarts the periodic tick after a number of cycles since the last counted tick.
+///
+/// Full periods are added to the tick count. If `enable` is set, the remaining cycles
+/// are accounted for by shortening the first period, so the tick phase is preserved.
+/// Otherwise, the counter is left stopped and the remaining cycles are lost.
+unsafe fn resume_periodic(
+    syst: &mut cortex_m::peripheral::SYST,
+    reload: u32,
+    elapsed: u64,
+    enable: bool,
+) {
+    if !enable {
+        SYSTICK_COUNTER += elapsed / (reload as u64 + 1);
+        syst.set_reload(reload);
+        syst.clear_current();
+        return;
+    }
+
+    let (ticks, first) = split_periods(reload, elapsed);
+    SYSTICK_COUNTER += ticks;
+
+    // The counter loads the reload value when enabled, so it can be restored immediately
+    syst.set_reload(first);
+    syst.clear_current();
+    syst.enable_counter();
+    syst.set_reload(reload);
+}
+
+/// Returns if timestamp `a` is before timestamp `b`, taking a counter wrap into account.
+///
+/// The result is only valid if both timestamps are less than half
+/// of the counter range (`i64::MAX`) apart.
+pub const fn is_before(a: u64, b: u64) -> bool {
+    (a.wrapping_sub(b) as i64) < 0
+}
+
+/// Set an interrupt callback function.
+///
+/// The provided callback function is called on each SysTick interrupt
+/// after updating the tick count and passed its value as argument
+#[cfg(not(feature = "no_callback"))]
+pub fn set_callback(callback: fn(u64)) {
+    unsafe {
+        CALLBACK_FN = Some(callback);
+    };
+}
+
+/// Replaces the interrupt callback function and returns the previous one.
+///
+/// The swap is atomic, so there's no tick without a callback function in between,
+/// unlike `clear_callback()` followed by `set_callback()`.
+#[cfg(not(feature = "no_callback"))]
+pub fn replace_callback(callback: fn(u64)) -> Option<fn(u64)> {
+    interrupt::free(|_| unsafe { CALLBACK_FN.replace(callback) })
+}
+
+/// Skips the callback function and handler until `resume_callback()` is called.
+///
+/// The ticks are still counted, so the time stays correct. The callback function and
+/// handler stay registered, but are not called for the ticks in between.
+#[cfg(not(feature = "no_callback"))]
+pub fn suspend_callback() {
+    unsafe {
+        CALLBACK_SUSPENDED = true;
+    };
+}
+
+/// Calls the callback function and handler again on each tick after `suspend_callback()`.
+#[cfg(not(feature = "no_callback"))]
+pub fn resume_callback() {
+    unsafe {
+        CALLBACK_SUSPENDED = false;
+    };
+}
+
+/// Sets which tick count is passed to the callback function and handler.
+///
+/// With `CallbackTiming::After`, which is the default, the count after the increment
+/// is passed, matching `ticks()`. With `CallbackTiming::Before`, the count before
+/// the increment is passed, identifying the tick period that ended at the wrap.
+/// Only the argument is affected, the count is always incremented before the call,
+/// so `ticks()` called within the callback returns the incremented value.
+#[cfg(not(feature = "no_callback"))]
+pub fn set_callback_timing(timing: CallbackTiming) {
+    unsafe {
+        CALLBACK_TIMING = timing;
+    };
+}
+
+/// Returns the tick count to pass to the callback function and handler.
+#[cfg(not(feature = "no_callback"))]
+unsafe fn callback_ticks(ticks: u64) -> u64 {
+    match CALLBACK_TIMING {
+        CallbackTiming::Before => ticks - 1,
+        CallbackTiming::After => ticks,
+    }
+}
+
+/// Clear the interrupt callback function.
+#[cfg(not(feature = "no_callback"))]
+pub fn clear_callback() {
+    unsafe {
+        CALLBACK_FN = None;
+    };
+}
+
+/// Advances the tick count without waiting for real time.
+///
+/// Intended for on-target tests of timeouts and schedulers only.
+/// The callback function is not called, use `advance_ticks_with_callback()` for that.
+#[cfg(feature = "test_hooks")]
+pub fn advance_ticks(n: u64) {
+    interrupt::free(|_| unsafe { SYSTICK_COUNTER += n });
+}
+
+/// Advances the tick count by `n` ticks, handling each of them like the interrupt.
+///
+/// The callback function, handler, deferred callback function and waiting async
+/// tasks are notified on each tick.
+#[cfg(all(feature = "test_hooks", not(feature = "no_callback")))]
+pub fn advance_ticks_with_callback(n: u64) {
+    for _ in 0..n {
+        interrupt::free(|_| unsafe { advance_tick() });
+    }
+}
+
+/// Counts a tick and notifies everything waiting for it, without the timer.
+#[cfg(any(test, all(feature = "test_hooks", not(feature = "no_callback"))))]
+unsafe fn advance_tick() {
+    SYSTICK_COUNTER += 1;
+    dispatch_tick();
+}
+
+/// Set an interrupt handler object.
+///
+/// The handler is called on each SysTick interrupt after the callback function.
+/// Since it's a shared static reference, e.g. a static instance of a scheduler,
+/// no allocation is needed.
+#[cfg(not(feature = "no_callback"))]
+pub fn set_handler(handler: &'static dyn TickHandler) {
+    unsafe {
+        TICK_HANDLER = Some(handler);
+    };
+}
+
+/// Clear the interrupt handler object.
+#[cfg(not(feature = "no_callback"))]
+pub fn clear_handler() {
+    unsafe {
+        TICK_HANDLER = None;
+    };
+}
+
+/// Set a callback function running outside of the interrupt.
+///
+/// The interrupt only marks the callback as pending, it is called by `service_deferred()`
+/// from the main loop or a low-priority handler. This keeps heavy per-tick work out of
+/// the SysTick interrupt. If `service_deferred()` is not called on each tick, the pending
+/// ticks are coalesced into a single call with the latest tick count.
+#[cfg(not(feature = "no_callback"))]
+pub fn set_deferred_callback(callback: fn(u64)) {
+    interrupt::free(|_| unsafe {
+        DEFERRED_FN = Some(callback);
+        DEFERRED_PENDING = 0;
+    });
+}
+
+/// Clear the deferred callback function.
+#[cfg(not(feature = "no_callback"))]
+pub fn clear_deferred_callback() {
+    interrupt::free(|_| unsafe {
+        DEFERRED_FN = None;
+        DEFERRED_PENDING = 0;
+    });
+}
+
+/// Sets if a PendSV exception is requested when the deferred callback function is pending.
+///
+/// Allows calling `service_deferred()` from the PendSV handler instead of the main loop.
+#[cfg(not(feature = "no_callback"))]
+pub fn set_deferred_pendsv(enable: bool) {
+    unsafe {
+        DEFERRED_PENDSV = enable;
+    };
+}
+
+/// Calls the deferred callback function if it is pending.
+///
+/// Returns the number of ticks covered by the call, which is 0 if nothing was pending
+/// and more than 1 if ticks were coalesced because the call was late.
+#[cfg(not(feature = "no_callback"))]
+pub fn service_deferred() -> u32 {
+    let (callback, pending) =
+        interrupt::free(|_| unsafe { (DEFERRED_FN, core::mem::replace(&mut DEFERRED_PENDING, 0)) });
+
+    match callback {
+        Some(callback) if pending > 0 => {
+            callback(ticks());
+            pending
+        }
+        _ => 0,
+    }
+}
+
+/// Requests a PendSV exception.
+///
+/// Intended for RTOS ports that make a scheduling decision in the tick callback or
+/// handler and perform the context switch in the PendSV handler. PendSV should be
+/// configured with the lowest priority, below SysTick and all other interrupts, so the
+/// context switch only happens after all interrupt handlers have completed.
+pub fn trigger_pendsv() {
+    cortex_m::peripheral::SCB::set_pendsv();
+}
+
+/// Returns if the callback function or handler ever took longer than the tick period.
+///
+/// A callback running into the next tick period delays the following interrupt,
+/// so ticks get lost when this happens repeatedly. The condition is detected by
+/// the next SysTick interrupt already being pending when the callback returns,
+/// so it is not detected in polling mode. The flag is latched.
+///
+/// The check is only done in debug builds, release builds always return `false`.
+#[cfg(not(feature = "no_callback"))]
+pub fn callback_overran() -> bool {
+    #[cfg(debug_assertions)]
+    return unsafe { CALLBACK_OVERRUN };
+
+    #[cfg(not(debug_assertions))]
+    false
+}
+
+/// Set a callback function for reconfiguration.
+///
+/// The provided callback function is called at the end of `reconfigure()`
+/// with the new clock and tick frequencies in Hz as arguments.
+pub fn set_reconfigure_callback(callback: fn(u32, u32)) {
+    unsafe {
+        RECONFIGURE_FN = Some(callback);
+    };
+}
+
+/// Clear the reconfigure callback function.
+pub fn clear_reconfigure_callback() {
+    unsafe {
+        RECONFIGURE_FN = None;
+    };
+}
+
+/// External interrupt call.
+///
+/// This function must be called from the external SysTick handler
+/// when the `irq_handler` feature is disabled. Does nothing when the
+/// timer is not initialized or was released with `free()`.
 #[cfg(not(feature = "irq_handler"))]
 pub fn interrupt() {
     irq();
 }
 
-/// Called on SysTick interrupt, either internally or via the `interrupt()` function.
+/// Returns if the SysTick interrupt stopped running at some point.
+///
+/// Detected when `clock_cycles()` or a function derived from it sees a second counter
+/// wrap before the interrupt counted the first one, meaning that the interrupt didn't
+/// run for at least a full tick period. `millis()` and `ticks()` don't read the
+/// counter, so this function checks for a wrap itself. Calling it repeatedly, e.g.
+/// from a low priority task, detects a stalled interrupt once two calls saw a wrap
+/// that the interrupt didn't count in between. Interrupts disabled for longer than a
+/// tick period while reading the time also set the flag, which is correct since a tick
+/// is lost then. The flag is latched. Not detected in polling mode.
+pub fn stalled() -> bool {
+    critical_section(|| unsafe {
+        if let Some(syst) = SYSTICK.as_ref() {
+            read_csr(syst);
+        }
+        STALLED
+    })
+}
+
+/// Returns the number of times missed ticks were detected.
+///
+/// Counts the events, not the ticks: each interrupt-masked delay that was compensated
+/// by `delay::delay_us_uninterrupted()` and each detection of `stalled()`.
+/// Can be used to find critical sections that are too long.
+pub fn missed_tick_count() -> u32 {
+    unsafe { MISSED_TICKS }
+}
+
+/// Resets the diagnostic counters and flags.
+///
+/// Affects `missed_tick_count()`, `spurious_interrupts()`, `stalled()` and, if
+/// available, `callback_overran()` and `monotonic_violations()`.
+pub fn reset_stats() {
+    interrupt::free(|_| unsafe {
+        MISSED_TICKS = 0;
+        SPURIOUS_CALLS = 0;
+        STALLED = false;
+
+        #[cfg(all(debug_assertions, not(feature = "no_callback")))]
+        {
+            CALLBACK_OVERRUN = false;
+        }
+
+        #[cfg(feature = "debug_monotonic")]
+        {
+            MONOTONIC_VIOLATIONS = 0;
+        }
+    });
+}
+
+/// Returns the number of interrupt calls that were ignored because the counter didn't wrap.
+///
+/// A nonzero value usually means that `interrupt()` is called from more than
+/// one handler, or the SysTick exception was triggered by software. The check is
+/// not done in polling mode and with the `single_context` feature.
+pub fn spurious_interrupts() -> u32 {
+    unsafe { SPURIOUS_CALLS }
+}
+
+/// Detects a counter wrap in polling mode.
+///
+/// Must be called in the main loop at least once per tick period when the counter
+/// was initialized with `init_polling_with_frequency()`. Does nothing otherwise.
+pub fn poll() {
+    if unsafe { !POLLING } {
+        return;
+    }
+
+    // The wrap is counted in the same critical section it is detected in, so a
+    // nested call from an interrupt can't count it again or see it half counted
+    let action = critical_section(|| unsafe {
+        let syst = SYSTICK.as_mut().unwrap();
+
+        // A wrap detected by reading the status register before is latched
+        WRAP_PENDING |= syst.has_wrapped();
+        if WRAP_PENDING {
+            account_wrap(syst)
+        } else {
+            WrapAction::None
+        }
+    });
+
+    unsafe { complete_wrap(action) };
+}
+
+/// Runs a closure in a critical section, unless the `single_context` feature is enabled.
+///
+/// Only used for accessing the tick count, operations reprogramming the timer
+/// always need a real critical section.
+#[inline(always)]
+fn critical_section<R>(f: impl FnOnce() -> R) -> R {
+    #[cfg(not(feature = "single_context"))]
+    return interrupt::free(|_| f());
+
+    #[cfg(feature = "single_context")]
+    f()
+}
+
+/// Called on SysTick interrupt, either internally, via the `interrupt()` function
+/// or from `poll()` in polling mode.
 fn irq() {
-    unsafe {
-        // Increase the counter
-        SYSTICK_COUNTER += 1;
+    // A spurious exception after `free()` must not panic in the handler
+    if unsafe { SYSTICK.is_none() } {
+        return;
+    }
+
+    let action = critical_section(|| unsafe { account_wrap(SYSTICK.as_mut().unwrap()) });
+
+    unsafe { complete_wrap(action) };
+}
+
+/// What remains to be done outside of the critical section after a wrap.
+enum WrapAction {
+    /// Nothing, the wrap was not counted.
+    None,
+    /// Dispatch the counted tick.
+    Tick,
+    /// Call the callback of the expired one-shot.
+    OneShot(fn()),
+}
+
+/// Accounts for a wrap of the counter. Must be called in a critical section.
+///
+/// Consumes the wrap, increments the tick count and sets the next dithered reload,
+/// so the tick count and the current value are always consistent for readers.
+unsafe fn account_wrap(syst: &mut cortex_m::peripheral::SYST) -> WrapAction {
+    if let Some(callback) = ONESHOT_FN {
+        // The one-shot interval is over, so account for it and resume the ticks
+        let (elapsed, _) = stop_and_read(syst, syst.rvr.read());
+        let elapsed = ONESHOT_START + elapsed;
+        ONESHOT_FN = None;
+        resume_periodic(syst, CLOCK_FREQ / TICK_FREQ - 1, elapsed, true);
+
+        return WrapAction::OneShot(callback);
+    }
+
+    // Read the status register to ensure COUNTFLAG is reset to 0
+    let wrapped = syst.has_wrapped() | core::mem::replace(&mut WRAP_PENDING, false);
+
+    // A call without a wrap comes from a second handler also calling this function
+    // or a spurious exception, counting it would make the time run too fast
+    #[cfg(not(feature = "single_context"))]
+    if !wrapped && !POLLING {
+        SPURIOUS_CALLS = SPURIOUS_CALLS.saturating_add(1);
+        return WrapAction::None;
+    }
+    #[cfg(feature = "single_context")]
+    let _ = wrapped;
+
+    if WAKEUP_ONLY {
+        // The interrupt only wakes up the core, time is frozen
+        return WrapAction::None;
+    }
+
+    // Increase the counter
+    SYSTICK_COUNTER += 1;
+
+    if DITHER {
+        // The next period is already loaded, so set the reload for the one after
+        syst.set_reload(period_reload(syst, SYSTICK_COUNTER + 1));
+    }
+
+    WrapAction::Tick
+}
+
+/// Runs the callbacks for an accounted wrap outside of the critical section.
+unsafe fn complete_wrap(action: WrapAction) {
+    match action {
+        WrapAction::None => {}
+        WrapAction::Tick => {
+            dispatch_tick();
 
-        // Read the status register to ensure COUNTFLAG is reset to 0
-        let _ = SYSTICK.as_mut().unwrap().has_wrapped();
+            // The next tick is already pending if the callback took too long
+            #[cfg(all(debug_assertions, not(feature = "no_callback")))]
+            if !CALLBACK_SUSPENDED && cortex_m::peripheral::SCB::is_pendst_pending() {
+                CALLBACK_OVERRUN = true;
+            }
+        }
+        WrapAction::OneShot(callback) => callback(),
+    }
+}
 
-        // Execute optional callback function
+/// Notifies everything waiting for a tick after the tick count was increased.
+///
+/// Called from the interrupt and `advance_ticks_with_callback()`.
+unsafe fn dispatch_tick() {
+    WRAP_LATCH = true;
+
+    #[cfg(feature = "async")]
+    asynch::on_tick();
+
+    // Execute optional callback function and handler
+    #[cfg(not(feature = "no_callback"))]
+    if !CALLBACK_SUSPENDED {
+        let ticks = callback_ticks(SYSTICK_COUNTER);
         if let Some(callback) = CALLBACK_FN {
-            callback(SYSTICK_COUNTER);
+            callback(ticks);
+        }
+        if let Some(handler) = TICK_HANDLER {
+            handler.on_tick(ticks);
+        }
+
+        if DEFERRED_FN.is_some() {
+            DEFERRED_PENDING = DEFERRED_PENDING.saturating_add(1);
+            if DEFERRED_PENDSV {
+                cortex_m::peripheral::SCB::set_pendsv();
+            }
         }
     }
 }
@@ -170,3 +1882,354 @@ fn irq() {
 fn SysTick() {
     irq();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate std;
+
+    /// Serializes the tests changing the global callback state.
+    static CALLBACK_STATE: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Returns the reload value for a clock and tick frequency.
+    fn reload(clock_freq: u32, tick_freq: u32) -> u32 {
+        clock_freq / tick_freq - 1
+    }
+
+    #[test]
+    fn tick_freq_range() {
+        for clock_freq in [8_000_000, 48_000_000, 168_000_000, 480_000_000] {
+            let max = max_tick_freq(clock_freq);
+            assert!(reload(clock_freq, max) >= 1);
+            assert_eq!(reload(clock_freq, max + 1), 0);
+
+            let min = min_tick_freq(clock_freq);
+            assert!(reload(clock_freq, min) <= MAX_RELOAD);
+            if min > 1 {
+                assert!(reload(clock_freq, min - 1) > MAX_RELOAD);
+            }
+
+            assert_eq!(check_frequencies(clock_freq, max), Ok(()));
+            assert_eq!(check_frequencies(clock_freq, min), Ok(()));
+            assert_eq!(
+                check_frequencies(clock_freq, max + 1),
+                Err(SysTickError::ClockTooLow)
+            );
+        }
+
+        assert_eq!(max_tick_freq(168_000_000), 84_000_000);
+        assert_eq!(min_tick_freq(168_000_000), 11);
+        assert_eq!(min_tick_freq(8_000_000), 1);
+        assert_eq!(
+            check_frequencies(168_000_000, 10),
+            Err(SysTickError::ReloadOutOfRange)
+        );
+    }
+
+    #[test]
+    fn required_clock() {
+        assert_eq!(required_clock_hz(1000, 10), 100_000_000);
+        assert_eq!(required_clock_hz(1000, 3), 333_333_334);
+        assert_eq!(required_clock_hz(1000, 1000), 1_000_000);
+
+        for (tick_freq, resolution_ns) in [(1000, 10), (1000, 3), (100, 7), (32768, 20)] {
+            let clock_freq = required_clock_hz(tick_freq, resolution_ns);
+            assert!(1_000_000_000 / clock_freq <= resolution_ns);
+            assert!(1_000_000_000 / (clock_freq - 1) >= resolution_ns);
+            assert!(reload(clock_freq, tick_freq) <= MAX_RELOAD);
+        }
+
+        // At least two cycles per tick
+        assert_eq!(required_clock_hz(1_000_000, 1000), 2_000_000);
+
+        // No clock frequency satisfies all constraints
+        assert_eq!(required_clock_hz(10, 1), 0);
+        assert_eq!(required_clock_hz(u32::MAX, 1000), 0);
+        assert_eq!(required_clock_hz(0, 10), 0);
+        assert_eq!(required_clock_hz(1000, 0), 0);
+    }
+
+    #[test]
+    fn is_before_across_wrap() {
+        assert!(is_before(1, 2));
+        assert!(!is_before(2, 1));
+        assert!(!is_before(5, 5));
+
+        assert!(is_before(u64::MAX, 0));
+        assert!(is_before(u64::MAX - 10, 10));
+        assert!(!is_before(0, u64::MAX));
+        assert!(!is_before(10, u64::MAX - 10));
+
+        // Valid up to half of the counter range
+        assert!(is_before(u64::MAX, i64::MAX as u64 - 1));
+        assert!(!is_before(u64::MAX, i64::MAX as u64 + 1));
+    }
+
+    #[test]
+    fn muldiv_without_overflow() {
+        // The intermediate products overflow a u64 multiplication
+        assert_eq!(muldiv_u64(u64::MAX, 1000000, 1000000), u64::MAX);
+        assert_eq!(muldiv_u64(u64::MAX / 2, 4, 8), u64::MAX / 4);
+        assert_eq!(muldiv_u64(1 << 40, 1 << 40, 1 << 60), 1 << 20);
+        assert_eq!(
+            muldiv_u64(20_000_000_000_000_000, 1000000000, 200_000_000),
+            100_000_000_000_000_000
+        );
+
+        // Truncation toward zero
+        assert_eq!(muldiv_u64(10, 2, 3), 6);
+        assert_eq!(muldiv_u64(1, 1, 2), 0);
+        assert_eq!(muldiv_u64(u64::MAX, 2, 3), 12297829382473034410);
+    }
+
+    /// Returns the result of `resolve_counter()` with a reload value of 167999.
+    fn resolve(ticks: u64, csr: u32, pending: bool, current: u32, reread: u32) -> (u64, u32) {
+        resolve_counter(ticks, csr, pending, current, || reread, || 167999)
+    }
+
+    #[test]
+    fn counter_before_start() {
+        // Cleared by init, no time has elapsed until the counter is started
+        assert_eq!(resolve(0, 0, false, 0, 0), (0, 167999));
+        assert_eq!(resolve(5, 0, false, 0, 0), (5, 167999));
+
+        // Stopped after running, the progress into the period is kept
+        assert_eq!(resolve(5, 0, false, 1000, 1000), (5, 1000));
+
+        // Started, the current value is used as is
+        assert_eq!(resolve(0, CSR_ENABLE, false, 1000, 990), (0, 1000));
+    }
+
+    #[test]
+    fn counter_wrap_while_reading() {
+        let csr = CSR_ENABLE | CSR_COUNTFLAG;
+
+        // Wrapped between reading the current value and the status register
+        assert_eq!(resolve(7, csr, true, 3, 167990), (8, 167990));
+
+        // Wrapped before reading the current value
+        assert_eq!(resolve(7, csr, true, 167995, 167990), (8, 167990));
+
+        // Wrap detected by an earlier read, the current value is already in the new period
+        assert_eq!(resolve(7, CSR_ENABLE, true, 167000, 166990), (8, 167000));
+
+        // The elapsed cycles never run backwards across the wrap
+        let before = 7 * 168000 + (167999 - 3);
+        let (ticks, current) = resolve(7, csr, true, 3, 167990);
+        assert!(ticks * 168000 + (167999 - current) as u64 > before);
+    }
+
+    // The async wakers are taken in a critical section, which panics on the host
+    #[cfg(not(any(feature = "no_callback", feature = "async")))]
+    #[test]
+    fn suspended_callback() {
+        use core::sync::atomic::{AtomicU32, Ordering};
+
+        static CALLS: AtomicU32 = AtomicU32::new(0);
+
+        let _lock = CALLBACK_STATE.lock().unwrap_or_else(|e| e.into_inner());
+
+        // The interrupt counts the tick from the timer registers, so the ticks are
+        // advanced like `advance_ticks_with_callback()` does. `ticks()` reads the
+        // count in a critical section, which panics on the host, so the counter is
+        // checked directly.
+        unsafe {
+            CALLBACK_FN = Some(|_| {
+                CALLS.fetch_add(1, Ordering::Relaxed);
+            });
+
+            advance_tick();
+            assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+            assert_eq!(SYSTICK_COUNTER, 1);
+
+            suspend_callback();
+            advance_tick();
+            advance_tick();
+            assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+            assert_eq!(SYSTICK_COUNTER, 3);
+            assert!(core::mem::replace(&mut WRAP_LATCH, false));
+
+            resume_callback();
+            advance_tick();
+            assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+            assert_eq!(SYSTICK_COUNTER, 4);
+
+            CALLBACK_FN = None;
+            SYSTICK_COUNTER = 0;
+        }
+    }
+
+    #[test]
+    fn interrupt_after_free() {
+        let _lock = CALLBACK_STATE.lock().unwrap_or_else(|e| e.into_inner());
+
+        // The peripheral is never set in the tests, which is the state after `free()`
+        assert!(unsafe { SYSTICK.is_none() });
+
+        #[cfg(not(feature = "no_callback"))]
+        unsafe {
+            CALLBACK_FN = Some(|_| panic!("callback function called"));
+        }
+
+        irq();
+        assert_eq!(unsafe { SYSTICK_COUNTER }, 0);
+
+        // A spurious exception with a one-shot pending also returns before
+        // accessing the peripheral
+        unsafe {
+            ONESHOT_FN = Some(|| panic!("one-shot callback called"));
+        }
+
+        irq();
+        assert_eq!(unsafe { SYSTICK_COUNTER }, 0);
+
+        unsafe {
+            ONESHOT_FN = None;
+        }
+
+        #[cfg(not(feature = "no_callback"))]
+        unsafe {
+            CALLBACK_FN = None;
+        }
+    }
+
+    #[test]
+    fn conversions_after_years() {
+        const CLOCK_FREQ: u32 = 200_000_000;
+        const YEAR_SECONDS: u64 = 365 * 24 * 3600;
+
+        for years in [1, 10] {
+            for tick_freq in [1000, 3, 7, 32768] {
+                let ticks = years * YEAR_SECONDS * tick_freq as u64;
+                let reload = CLOCK_FREQ / tick_freq - 1;
+
+                // First and last cycle of the tick period
+                for elapsed in [0, reload as u64] {
+                    let cycles = dithered_period_start(CLOCK_FREQ, tick_freq, ticks) + elapsed;
+
+                    let quotient = (CLOCK_FREQ / tick_freq) as u128;
+                    let remainder = (CLOCK_FREQ % tick_freq) as u128;
+                    let reference = quotient * ticks as u128
+                        + remainder * (ticks - 1) as u128 / tick_freq as u128
+                        + elapsed as u128;
+                    assert_eq!(cycles as u128, reference);
+
+                    let micros = muldiv_u64(cycles, 1000000, CLOCK_FREQ as u64);
+                    assert_eq!(micros as u128, reference * 1000000 / CLOCK_FREQ as u128);
+
+                    let nanos = muldiv_u64(cycles, 1000000000, CLOCK_FREQ as u64);
+                    assert_eq!(nanos as u128, reference * 1000000000 / CLOCK_FREQ as u128);
+                }
+
+                let millis = muldiv_u64(ticks, 1000, tick_freq as u64);
+                assert_eq!(millis, years * YEAR_SECONDS * 1000);
+            }
+        }
+    }
+
+    #[test]
+    fn tick_freq_change_continuity() {
+        const CLOCK_FREQ: u32 = 168_000_000;
+
+        // Returns the clock cycles right after resuming with a tick frequency
+        let resume = |cycles: u64, tick_freq: u32| -> u64 {
+            let reload = CLOCK_FREQ / tick_freq - 1;
+            let (ticks, first) = split_periods(reload, cycles);
+            ticks * (reload as u64 + 1) + (reload - first) as u64
+        };
+
+        for cycles in [
+            0,
+            1,
+            16799,
+            16800,
+            167999,
+            168000,
+            1_000_000_007,
+            u32::MAX as u64,
+        ] {
+            // Same as `with_tick_freq()` for a finer tick and back
+            let during = resume(cycles, 10000);
+            let after = resume(during, 1000);
+
+            assert!(during >= cycles && during - cycles <= 1);
+            assert!(after >= during && after - during <= 1);
+        }
+    }
+
+    #[test]
+    fn cycles_with_observed_wraps() {
+        let reload = 167999;
+
+        assert_eq!(cycles_since(100000, 0, reload, 40000), 60000);
+        assert_eq!(cycles_since(100000, 1, reload, 160000), 108000);
+        assert_eq!(cycles_since(100000, 1000, reload, 100000), 168_000_000);
+        assert_eq!(
+            cycles_since(0, u32::MAX, reload, reload),
+            721_554_505_560_000 - reload as u64
+        );
+
+        // Inconsistent values without a wrap don't underflow
+        assert_eq!(cycles_since(1000, 0, reload, 2000), 0);
+    }
+
+    #[test]
+    fn ticks_while_masked() {
+        let period = 168000;
+
+        // No wrap, or only the one the pending interrupt counts
+        assert_eq!(masked_ticks(1000, 500, period, false), 0);
+        assert_eq!(masked_ticks(1000, 1000, period, false), 0);
+        assert_eq!(masked_ticks(1000, 1001, period, false), 0);
+        assert_eq!(masked_ticks(1000, 169000, period, false), 0);
+        assert_eq!(masked_ticks(1000, 169001, period, false), 1);
+        assert_eq!(masked_ticks(1000, 1000 + 10 * 168000, period, false), 9);
+
+        // A wrap latched before is counted by the pending interrupt instead
+        assert_eq!(masked_ticks(1000, 500, period, true), 0);
+        assert_eq!(masked_ticks(1000, 1001, period, true), 1);
+        assert_eq!(masked_ticks(1000, 1000 + 10 * 168000, period, true), 10);
+    }
+
+    #[test]
+    fn timescale_32768() {
+        const HZ: u32 = 32768;
+
+        assert_eq!(to_timescale(1000000, HZ), 32768);
+        assert_eq!(from_timescale(32768, HZ), 1000000);
+        assert_eq!(to_timescale(15625, HZ), 512);
+        assert_eq!(from_timescale(512, HZ), 15625);
+        assert_eq!(from_timescale(1, HZ), 30);
+
+        // Truncation loses at most one count or one count period on the round trip
+        for ticks in (0..100000).chain(u64::MAX / 1000000 - 1000..u64::MAX / 1000000) {
+            let back = to_timescale(from_timescale(ticks, HZ), HZ);
+            assert!(back <= ticks && ticks - back <= 1, "{ticks} -> {back}");
+        }
+        for us in (0..100000).chain(365 * 24 * 3600 * 1000000..365 * 24 * 3600 * 1000000 + 1000) {
+            let back = from_timescale(to_timescale(us, HZ), HZ);
+            assert!(back <= us && us - back <= 31, "{us} -> {back}");
+        }
+    }
+
+    #[test]
+    fn dithered_average_period() {
+        const TICKS: u64 = 100000;
+
+        for (clock_freq, tick_freq) in [(32768, 1000), (48_000_000, 7), (168_000_000, 1000)] {
+            let reload = clock_freq / tick_freq - 1;
+            let mut total = 0;
+
+            for ticks in 0..TICKS {
+                let period_reload = dithered_period_reload(clock_freq, tick_freq, ticks);
+                assert!(period_reload == reload || period_reload == reload + 1);
+                total += period_reload as u64 + 1;
+            }
+
+            let exact = TICKS as u128 * clock_freq as u128 / tick_freq as u128;
+            assert!(exact.abs_diff(total as u128) < 2);
+            assert_eq!(total, dithered_period_start(clock_freq, tick_freq, TICKS));
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! CPU load estimation from idle time.
+//!
+//! Only the time spent in `idle()` counts as idle, e.g. called from the main loop
+//! when there is nothing to do, or indirectly via `delay::delay_ms_wfi()`.
+//! Time spent waiting in busy loops or in other sleep primitives counts as load.
+
+use cortex_m::interrupt;
+
+use crate::{clock_cycles, generation};
+
+/// Clock cycles spent in `idle()` in the current window.
+static mut IDLE_CYCLES: u64 = 0;
+
+/// Clock cycles at the start of the current window.
+static mut WINDOW_START: u64 = 0;
+
+/// Reset generation of the tick count in the current window.
+static mut WINDOW_GENERATION: u32 = 0;
+
+/// Waits for an interrupt and accounts the time as idle.
+///
+/// The interrupt that wakes up the core is handled after the idle time is measured,
+/// so the time spent in its handler counts as load.
+/// Must not be used in polling mode, where the SysTick interrupt does not wake up the core.
+pub fn idle() {
+    interrupt::free(|_| {
+        let start = clock_cycles();
+        cortex_m::asm::wfi();
+        let idle = clock_cycles() - start;
+
+        unsafe { IDLE_CYCLES += idle };
+    });
+}
+
+/// Returns the CPU load in percent since the last call and starts a new window.
+///
+/// Returns 0.0 on the first call after init and after the tick count was reset, see
+/// `generation()`, because the clock cycles of the last window can't be compared then.
+pub fn cpu_load_percent() -> f32 {
+    let (idle, elapsed) = interrupt::free(|_| unsafe {
+        let now = clock_cycles();
+        let elapsed = if WINDOW_GENERATION == generation() {
+            now.saturating_sub(WINDOW_START)
+        } else {
+            0
+        };
+        let idle = IDLE_CYCLES;
+
+        WINDOW_START = now;
+        WINDOW_GENERATION = generation();
+        IDLE_CYCLES = 0;
+
+        (idle, elapsed)
+    });
+
+    if elapsed == 0 {
+        return 0.0;
+    }
+
+    (100.0 * (1.0 - idle as f32 / elapsed as f32)).clamp(0.0, 100.0)
+}
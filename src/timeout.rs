@@ -0,0 +1,258 @@
+//! Timeouts for spin loops and repeated operations.
+
+use crate::{clock_cycles, is_before, millis, ticks, SysTickError};
+
+/// Upper bound in clock cycles for spin-waiting on a condition.
+///
+/// Checking the guard only reads the clock cycles, which is cheaper than converting
+/// to microseconds on each iteration of a tight loop.
+#[derive(Debug, Clone, Copy)]
+pub struct CycleGuard {
+    /// Clock cycles at which the guard expires.
+    deadline: u64,
+}
+
+impl CycleGuard {
+    /// Returns a guard expiring after a number of clock cycles from now.
+    pub fn new(max_cycles: u64) -> Self {
+        Self::starting_at(clock_cycles(), max_cycles)
+    }
+
+    /// Returns a guard expiring after a number of clock cycles from `now`.
+    fn starting_at(now: u64, max_cycles: u64) -> Self {
+        Self {
+            deadline: now.wrapping_add(max_cycles),
+        }
+    }
+
+    /// Returns if the maximum number of cycles has passed.
+    pub fn expired(&self) -> bool {
+        self.expired_at(clock_cycles())
+    }
+
+    /// Returns if the maximum number of cycles has passed at `now`.
+    fn expired_at(&self, now: u64) -> bool {
+        !is_before(now, self.deadline)
+    }
+}
+
+/// Reusable timeout in milliseconds that can be rearmed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OneShot {
+    /// Time in milliseconds at which the timeout expires, `None` if not armed.
+    deadline: Option<u64>,
+}
+
+impl OneShot {
+    /// Returns a timeout that is not armed.
+    pub const fn new() -> Self {
+        Self { deadline: None }
+    }
+
+    /// Arms the timeout to expire after a number of milliseconds from now.
+    ///
+    /// A pending timeout is replaced.
+    pub fn arm_ms(&mut self, value: u32) {
+        self.arm_ms_at(millis(), value);
+    }
+
+    /// Arms the timeout to expire after a number of milliseconds from `now`.
+    fn arm_ms_at(&mut self, now: u64, value: u32) {
+        self.deadline = Some(now + value as u64);
+    }
+
+    /// Disarms the timeout.
+    pub fn disarm(&mut self) {
+        self.deadline = None;
+    }
+
+    /// Returns if the timeout is armed, including when it has expired.
+    pub fn is_armed(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    /// Returns if the timeout is armed and the deadline has passed.
+    pub fn expired(&self) -> bool {
+        self.expired_at(millis())
+    }
+
+    /// Returns if the timeout is armed and the deadline has passed at `now`.
+    fn expired_at(&self, now: u64) -> bool {
+        match self.deadline {
+            Some(deadline) => !is_before(now, deadline),
+            None => false,
+        }
+    }
+}
+
+/// Deadline in ticks, without any conversion to time units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Deadline {
+    /// Tick count at which the deadline expires.
+    ticks: u64,
+}
+
+impl Deadline {
+    /// Returns a deadline expiring after a number of ticks from now.
+    pub fn in_ticks(n: u64) -> Self {
+        Self::starting_at(ticks(), n)
+    }
+
+    /// Returns a deadline expiring after a number of ticks from `now`.
+    fn starting_at(now: u64, n: u64) -> Self {
+        Self {
+            ticks: now.wrapping_add(n),
+        }
+    }
+
+    /// Returns if the deadline has passed.
+    pub fn expired(&self) -> bool {
+        self.expired_at(ticks())
+    }
+
+    /// Returns if the deadline has passed at the tick count `now`.
+    fn expired_at(&self, now: u64) -> bool {
+        !is_before(now, self.ticks)
+    }
+}
+
+/// Waits until `predicate` returns `true` or a number of ticks have passed.
+///
+/// Returns `SysTickError::TimedOut` if the predicate didn't return `true` in time.
+/// The predicate is checked at least once, even if `n` is 0.
+pub fn wait_ticks(n: u64, predicate: impl FnMut() -> bool) -> Result<(), SysTickError> {
+    wait_ticks_with(ticks, n, predicate)
+}
+
+/// Same as `wait_ticks()` with the tick count read from `now`.
+fn wait_ticks_with(
+    mut now: impl FnMut() -> u64,
+    n: u64,
+    mut predicate: impl FnMut() -> bool,
+) -> Result<(), SysTickError> {
+    let deadline = Deadline::starting_at(now(), n);
+
+    loop {
+        if predicate() {
+            return Ok(());
+        }
+        if deadline.expired_at(now()) {
+            return Err(SysTickError::TimedOut);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycle_guard() {
+        let guard = CycleGuard::starting_at(1000, 10000);
+        assert!(!guard.expired_at(1000));
+        assert!(!guard.expired_at(10999));
+        assert!(guard.expired_at(11000));
+        assert!(guard.expired_at(20000));
+
+        let guard = CycleGuard::starting_at(0, 0);
+        assert!(guard.expired_at(0));
+    }
+
+    #[test]
+    fn cycle_guard_across_wrap() {
+        let guard = CycleGuard::starting_at(u64::MAX - 100, 1000);
+        assert!(!guard.expired_at(u64::MAX));
+        assert!(!guard.expired_at(898));
+        assert!(guard.expired_at(899));
+    }
+
+    #[test]
+    fn one_shot() {
+        let mut timeout = OneShot::new();
+        assert!(!timeout.is_armed());
+        assert!(!timeout.expired_at(0));
+        assert!(!timeout.expired_at(u64::MAX));
+
+        timeout.arm_ms_at(1000, 500);
+        assert!(timeout.is_armed());
+        assert!(!timeout.expired_at(1499));
+        assert!(timeout.expired_at(1500));
+
+        // Stays armed after expiring until disarmed
+        assert!(timeout.is_armed());
+        timeout.disarm();
+        assert!(!timeout.is_armed());
+        assert!(!timeout.expired_at(1500));
+    }
+
+    #[test]
+    fn one_shot_rearm() {
+        let mut timeout = OneShot::default();
+
+        timeout.arm_ms_at(1000, 500);
+        timeout.arm_ms_at(1200, 500);
+        assert!(!timeout.expired_at(1500));
+        assert!(timeout.expired_at(1700));
+
+        // Rearming after expiry starts a new timeout
+        timeout.arm_ms_at(2000, 0);
+        assert!(timeout.expired_at(2000));
+        timeout.arm_ms_at(2000, 100);
+        assert!(!timeout.expired_at(2099));
+        assert!(timeout.expired_at(2100));
+    }
+
+    #[test]
+    fn deadline() {
+        let deadline = Deadline::starting_at(100, 10);
+        assert!(!deadline.expired_at(100));
+        assert!(!deadline.expired_at(109));
+        assert!(deadline.expired_at(110));
+
+        assert!(Deadline::starting_at(100, 0).expired_at(100));
+
+        let deadline = Deadline::starting_at(u64::MAX - 4, 10);
+        assert!(!deadline.expired_at(u64::MAX));
+        assert!(!deadline.expired_at(4));
+        assert!(deadline.expired_at(5));
+    }
+
+    #[test]
+    fn wait_for_ticks() {
+        // The tick count advances by one on each read
+        let mut tick = 0;
+        let mut clock = || {
+            tick += 1;
+            tick
+        };
+
+        let mut checks = 0;
+        let result = wait_ticks_with(&mut clock, 5, || {
+            checks += 1;
+            checks == 3
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(checks, 3);
+
+        let mut checks = 0;
+        let result = wait_ticks_with(&mut clock, 5, || {
+            checks += 1;
+            false
+        });
+        assert_eq!(result, Err(SysTickError::TimedOut));
+        assert_eq!(checks, 5);
+
+        // The predicate is checked once even without any ticks to wait
+        let mut checks = 0;
+        let result = wait_ticks_with(
+            || 0,
+            0,
+            || {
+                checks += 1;
+                false
+            },
+        );
+        assert_eq!(result, Err(SysTickError::TimedOut));
+        assert_eq!(checks, 1);
+    }
+}
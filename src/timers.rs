@@ -0,0 +1,114 @@
+//! Software timers serviced from the main loop.
+//!
+//! The SysTick interrupt only advances the time, the timer callbacks are run by
+//! `poll()` in thread context. This keeps heavy work out of the interrupt, at the
+//! cost of an added latency of up to one main loop iteration.
+//!
+//! All functions of this module must be called from the same context, usually
+//! the main loop, and not from interrupt handlers.
+
+use crate::{is_before, millis};
+
+/// Maximum number of timers running at the same time.
+pub const MAX_TIMERS: usize = 8;
+
+/// Id of a running timer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    /// Slot of the timer.
+    index: usize,
+    /// Sequence number to distinguish timers using the same slot.
+    sequence: u32,
+}
+
+/// State of a running timer.
+#[derive(Clone, Copy)]
+struct Timer {
+    /// Time in milliseconds when the timer is due.
+    deadline: u64,
+    /// Period in milliseconds, 0 for one-shot timers.
+    period: u32,
+    /// Function called when the timer is due.
+    callback: fn(),
+    /// Sequence number assigned on start.
+    sequence: u32,
+}
+
+/// Running timers, `None` for unused slots.
+static mut TIMERS: [Option<Timer>; MAX_TIMERS] = [None; MAX_TIMERS];
+
+/// Sequence number of the next started timer.
+static mut NEXT_SEQUENCE: u32 = 0;
+
+/// Starts a timer calling `callback` once after a number of milliseconds.
+///
+/// Returns `None` if `MAX_TIMERS` timers are already running.
+pub fn start_once(delay_ms: u32, callback: fn()) -> Option<TimerId> {
+    start(delay_ms, 0, callback)
+}
+
+/// Starts a timer calling `callback` periodically.
+///
+/// Returns `None` if `MAX_TIMERS` timers are already running.
+pub fn start_periodic(period_ms: u32, callback: fn()) -> Option<TimerId> {
+    start(period_ms, period_ms.max(1), callback)
+}
+
+/// Stops a timer. Does nothing if it has already expired.
+pub fn cancel(id: TimerId) {
+    let timers = unsafe { &mut TIMERS };
+
+    if matches!(timers[id.index], Some(timer) if timer.sequence == id.sequence) {
+        timers[id.index] = None;
+    }
+}
+
+/// Runs the callbacks of all due timers.
+///
+/// Must be called regularly from the main loop. Periodic timers are rescheduled
+/// relative to their previous deadline, so they don't drift. If `poll()` was not
+/// called for more than one period, the missed calls are skipped.
+pub fn poll() {
+    let now = millis();
+
+    // The callbacks may start or cancel timers, so no reference is held while calling them
+    #[allow(clippy::needless_range_loop)]
+    for index in 0..MAX_TIMERS {
+        let Some(mut timer) = (unsafe { TIMERS[index] }) else {
+            continue;
+        };
+
+        if is_before(now, timer.deadline) {
+            continue;
+        }
+
+        if timer.period == 0 {
+            unsafe { TIMERS[index] = None };
+        } else {
+            while !is_before(now, timer.deadline) {
+                timer.deadline += timer.period as u64;
+            }
+            unsafe { TIMERS[index] = Some(timer) };
+        }
+
+        (timer.callback)();
+    }
+}
+
+/// Adds a timer to a free slot.
+fn start(delay_ms: u32, period: u32, callback: fn()) -> Option<TimerId> {
+    let timers = unsafe { &mut TIMERS };
+    let index = timers.iter().position(|t| t.is_none())?;
+
+    let sequence = unsafe { NEXT_SEQUENCE };
+    unsafe { NEXT_SEQUENCE = sequence.wrapping_add(1) };
+
+    timers[index] = Some(Timer {
+        deadline: millis() + delay_ms as u64,
+        period,
+        callback,
+        sequence,
+    });
+
+    Some(TimerId { index, sequence })
+}
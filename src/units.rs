@@ -0,0 +1,68 @@
+//! Frequency units for the init functions.
+
+/// Frequency in Hz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Hertz(pub u32);
+
+impl Hertz {
+    /// Returns the frequency in Hz.
+    pub const fn to_hz(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<Hertz> for u32 {
+    fn from(value: Hertz) -> Self {
+        value.0
+    }
+}
+
+/// Returns a frequency in Hz.
+pub const fn hz(value: u32) -> Hertz {
+    Hertz(value)
+}
+
+/// Returns a frequency in kHz.
+///
+/// Panics if the frequency doesn't fit into `u32`, at compile time in const contexts.
+pub const fn khz(value: u32) -> Hertz {
+    match value.checked_mul(1000) {
+        Some(value) => Hertz(value),
+        None => panic!("frequency in kHz out of range"),
+    }
+}
+
+/// Returns a frequency in MHz.
+///
+/// Panics if the frequency doesn't fit into `u32`, at compile time in const contexts.
+pub const fn mhz(value: u32) -> Hertz {
+    match value.checked_mul(1000000) {
+        Some(value) => Hertz(value),
+        None => panic!("frequency in MHz out of range"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn units() {
+        assert_eq!(hz(32768).to_hz(), 32768);
+        assert_eq!(khz(1).to_hz(), 1000);
+        assert_eq!(mhz(168).to_hz(), 168000000);
+        assert_eq!(mhz(4294).to_hz(), 4294000000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mhz_out_of_range() {
+        let _ = mhz(5000);
+    }
+
+    #[test]
+    #[should_panic]
+    fn khz_out_of_range() {
+        let _ = khz(4294968);
+    }
+}